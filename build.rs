@@ -1,7 +1,45 @@
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//FNV-1a over the manifest and every source file, so the lobby can tell builds apart
+fn hash_sources(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).expect("Can't read source dir") {
+        let path = entry.expect("Invalid fs entry").path();
+        if path.is_dir() {
+            hash_sources(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+fn build_hash(manifest_dir: &Path) -> u64 {
+    let mut files = vec![manifest_dir.join("Cargo.toml")];
+    hash_sources(&manifest_dir.join("src"), &mut files);
+    files.sort();
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for file in files {
+        let relative = file.strip_prefix(manifest_dir).unwrap().to_string_lossy().replace("\\", "/");
+        let contents = fs::read(&file).expect("Can't read source file");
+        //CRLF counts as LF, so Windows and Unix checkouts of the same commit get the same hash
+        let contents: Vec<u8> = contents.iter().enumerate()
+            .filter(|&(i, &byte)| !(byte == b'\r' && contents.get(i + 1) == Some(&b'\n')))
+            .map(|(_, &byte)| byte).collect();
+        for byte in relative.as_bytes().iter().chain(contents.iter()) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
 //WARNING: min-gw not currently set up, only msvc.
 fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    println!("cargo:rustc-env=CHRONOX_BUILD_HASH={:016x}", build_hash(&manifest_dir));
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-changed=build.rs");
     let target = env::var("TARGET").unwrap();
     if target.contains("pc-windows") {
         let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
@@ -0,0 +1,523 @@
+use simulation::*;
+use std::cmp::{min, max};
+use std::fmt::Debug;
+use std::sync::Arc;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error;
+
+//how much damage each side's units do in a tick of fighting, in hundredths of a kill.
+//A level picks one of the built in rules below by name; anything else can be plugged into CombatConfig::resolver,
+//but only named rules survive saving (a save or replay naming an unknown rule won't load).
+pub trait CombatResolver: Debug + Send + Sync{
+    fn name(&self) -> &'static str;
+    fn damage(&self, count: &PlayerArr<u32>, teams: &PlayerArr<Team>) -> PlayerArr<u32>;
+    //two armies fighting it out with nothing else around, as on a lane: what's left of each, never more than it started with.
+    //By default it's fought tick by tick the way it would be on an undefended planet.
+    fn settle(&self, a: (Player, u32), b: (Player, u32), teams: &PlayerArr<Team>) -> (u32, u32){
+        let (mut count, mut progress) = (PlayerArr::new(0), PlayerArr::new(0));
+        count[a.0] = a.1;
+        count[b.0] = b.1;
+        while count[a.0] > 0 && count[b.0] > 0 {
+            let damage = self.damage(&count, teams);
+            if damage[a.0] == 0 && damage[b.0] == 0 {
+                break;
+            }
+            progress[a.0] += damage[a.0];
+            progress[b.0] += damage[b.0];
+            let (a_kills, b_kills) = (progress[a.0]/100, progress[b.0]/100);
+            progress[a.0] -= 100*a_kills;
+            progress[b.0] -= 100*b_kills;
+            count[b.0] -= min(a_kills, count[b.0]);
+            count[a.0] -= min(b_kills, count[a.0]);
+        }
+        (count[a.0], count[b.0])
+    }
+}
+//Lanchester's square law: every unit does the same damage, so a side's losses go with the size of the enemy.
+//50 against 30 leaves 40 (the square root of 50*50 - 30*30).
+#[derive(Debug)]
+pub struct SquareLaw;
+impl CombatResolver for SquareLaw{
+    fn name(&self) -> &'static str {"SquareLaw"}
+    fn damage(&self, count: &PlayerArr<u32>, _teams: &PlayerArr<Team>) -> PlayerArr<u32>{
+        *count
+    }
+    //the bigger army is left with the square root of the difference of the squares, an even fight wipes out both
+    fn settle(&self, a: (Player, u32), b: (Player, u32), _teams: &PlayerArr<Team>) -> (u32, u32){
+        let (big, small) = (max(a.1, b.1) as u64, min(a.1, b.1) as u64);
+        let left = isqrt(big*big - small*small) as u32;
+        if a.1 > b.1 {(left, 0)} else {(0, left)}
+    }
+}
+//Lanchester's linear law: units pair off, so everyone loses at the same rate and numbers only count one for one.
+//50 against 30 leaves 20. Each unit's damage is scaled by the enemy's share of the battle.
+#[derive(Debug)]
+pub struct LinearLaw;
+impl CombatResolver for LinearLaw{
+    fn name(&self) -> &'static str {"LinearLaw"}
+    fn damage(&self, count: &PlayerArr<u32>, teams: &PlayerArr<Team>) -> PlayerArr<u32>{
+        let sides = Player::values().filter(|&p| count[p] > 0).collect();
+        let team_count = find_teams(&sides, teams).len() as u64;
+        let total: u64 = count.iter().map(|&c| c as u64).sum();
+        let enemies = |p: Player| Player::values().filter(|&q| teams[q] != teams[p]).map(|q| count[q] as u64).sum::<u64>();
+        Player::map_from_fn(|p| {
+            if count[p] == 0 || team_count < 2 {
+                0
+            } else {
+                (count[p] as u64*enemies(p)*team_count/((team_count - 1)*total)) as u32
+            }
+        })
+    }
+    fn settle(&self, a: (Player, u32), b: (Player, u32), _teams: &PlayerArr<Team>) -> (u32, u32){
+        (a.1 - min(a.1, b.1), b.1 - min(a.1, b.1))
+    }
+}
+//not one of Lanchester's: units do more damage the bigger a share of the battle their team has,
+//so concentrating forces pays off even more than under the square law. With even teams it's the same as SquareLaw.
+#[derive(Debug)]
+pub struct Concentration;
+impl CombatResolver for Concentration{
+    fn name(&self) -> &'static str {"Concentration"}
+    fn damage(&self, count: &PlayerArr<u32>, teams: &PlayerArr<Team>) -> PlayerArr<u32>{
+        let sides = Player::values().filter(|&p| count[p] > 0).collect();
+        let team_count = find_teams(&sides, teams).len() as u64;
+        let total: u64 = count.iter().map(|&c| c as u64).sum();
+        let team_total = |p: Player| Player::values().filter(|&q| teams[q] == teams[p]).map(|q| count[q] as u64).sum::<u64>();
+        Player::map_from_fn(|p| {
+            if count[p] == 0 {
+                0
+            } else {
+                (count[p] as u64*team_total(p)*team_count/total) as u32
+            }
+        })
+    }
+}
+//the built in rule with that name
+pub fn named_resolver(name: &str) -> Option<Arc<dyn CombatResolver>>{
+    match name {
+        "SquareLaw" => Some(Arc::new(SquareLaw)),
+        "LinearLaw" => Some(Arc::new(LinearLaw)),
+        "Concentration" => Some(Arc::new(Concentration)),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CombatConfig{
+    pub resolver: Arc<dyn CombatResolver>,
+    pub defender_advantage: u32, //percent of normal damage for the planet owner's team, on top of the planet's own defense
+    pub min_duration: u32, //ticks a battle lasts at least: until then every team keeps one unit
+    pub lane_battles: bool, //enemy armies going opposite ways on a lane fight where they meet, instead of passing through
+    pub merge_armies: bool, //a player's armies going the same way on a lane join up when they reach each other
+}
+impl Default for CombatConfig{
+    fn default() -> CombatConfig{
+        CombatConfig{resolver: Arc::new(SquareLaw), defender_advantage: 100, min_duration: 0, lane_battles: false, merge_armies: false}
+    }
+}
+impl PartialEq for CombatConfig{
+    fn eq(&self, other: &CombatConfig) -> bool{
+        self.resolver.name() == other.resolver.name() && self.defender_advantage == other.defender_advantage &&
+            self.min_duration == other.min_duration && self.lane_battles == other.lane_battles && self.merge_armies == other.merge_armies
+    }
+}
+//how CombatConfig is written in a level's [combat] section, a save or a replay. Anything left out is the default.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct CombatSettings{
+    rule: String,
+    defender_advantage: u32,
+    min_duration: u32,
+    lane_battles: bool,
+    merge_armies: bool,
+}
+impl Default for CombatSettings{
+    fn default() -> CombatSettings{
+        CombatSettings::from(&CombatConfig::default())
+    }
+}
+impl<'a> From<&'a CombatConfig> for CombatSettings{
+    fn from(c: &CombatConfig) -> CombatSettings{
+        CombatSettings{rule: c.resolver.name().to_string(), defender_advantage: c.defender_advantage, min_duration: c.min_duration,
+            lane_battles: c.lane_battles, merge_armies: c.merge_armies}
+    }
+}
+impl Serialize for CombatConfig{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>{
+        CombatSettings::from(self).serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for CombatConfig{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CombatConfig, D::Error>{
+        let s = CombatSettings::deserialize(deserializer)?;
+        let resolver = named_resolver(&s.rule).ok_or_else(|| D::Error::custom(format!("unknown combat rule {}", s.rule)))?;
+        Ok(CombatConfig{resolver, defender_advantage: s.defender_advantage, min_duration: s.min_duration,
+            lane_battles: s.lane_battles, merge_armies: s.merge_armies})
+    }
+}
+
+//one tick of fighting at a planet with more than one team on it.
+//Everyone's damage comes from the units there at the start of the tick, and the kills land all at once,
+//so the order players are looked at in doesn't matter.
+pub fn fight(planet: &mut Planet, teams: &PlayerArr<Team>, config: &CombatConfig, total_dead: &mut PlayerArr<u32>){
+    let sides = find_sides_node(planet);
+    let teams_found = find_teams(&sides, teams);
+    let damage = config.resolver.damage(&planet.count, teams);
+    let defender = teams[planet.owner];
+    let defense = planet.defense_rate()*config.defender_advantage/100;
+    let mut kills_on: Vec<(Team, u32)> = teams_found.iter().map(|&t| (t, 0)).collect();
+    for &p in &sides {
+        planet.fight_progess[p] += if teams[p] == defender {damage[p]*defense/100} else {damage[p]};
+        let kills = planet.fight_progess[p]/100;
+        planet.fight_progess[p] -= 100*kills;
+        for &mut (team, ref mut k) in &mut kills_on {
+            if team != teams[p] {
+                *k += kills;
+            }
+        }
+    }
+    planet.battle_ticks += 1;
+    for (team, kills) in kills_on {
+        let kills = if planet.battle_ticks < config.min_duration {
+            let left = Player::values().filter(|&p| teams[p] == team).map(|p| planet.count[p]).sum::<u32>();
+            min(kills, left - 1)
+        } else {
+            kills
+        };
+        planet.kill_from_team(team, kills, teams, total_dead);
+    }
+}
+
+//armies going opposite ways on a lane that have reached each other fight it out on the spot, settled at once by the level's rule.
+//Every crossing that tick is settled from the counts at the start of it, so the order of the groups doesn't matter:
+//a group that has reached several enemies splits itself between them by their size (rounded down, the rest sit it out).
+pub fn lane_fight(groups: &mut Vec<ArmyGroup>, length: i32, teams: &PlayerArr<Team>, resolver: &dyn CombatResolver, time: ChronalTime, clashes: &mut Vec<Clash>, total_dead: &mut PlayerArr<u32>){
+    let crossed = |a: &ArmyGroup, b: &ArmyGroup| a.direction == DIR::FORWARD && b.direction == DIR::BACKWARD && a.count > 0 && b.count > 0 &&
+        teams[a.player] != teams[b.player] && a.progress + b.progress >= length;
+    let mut pairs = Vec::new();
+    for i in 0..groups.len() {
+        for j in 0..groups.len() {
+            if crossed(&groups[i], &groups[j]) {
+                pairs.push((i, j));
+            }
+        }
+    }
+    let mut facing = vec![0u64; groups.len()];
+    for &(i, j) in &pairs {
+        facing[i] += groups[j].count as u64;
+        facing[j] += groups[i].count as u64;
+    }
+    let mut lost = vec![0u32; groups.len()];
+    for &(i, j) in &pairs {
+        let (a, b) = (groups[i], groups[j]);
+        let a_share = (a.count as u64*b.count as u64/facing[i]) as u32;
+        let b_share = (b.count as u64*a.count as u64/facing[j]) as u32;
+        let (a_left, b_left) = resolver.settle((a.player, a_share), (b.player, b_share), teams);
+        //a rule that hands back more than it was given can't make units out of nothing
+        let (a_left, b_left) = (min(a_left, a_share), min(b_left, b_share));
+        lost[i] += a_share - a_left;
+        lost[j] += b_share - b_left;
+        let dead = a_share + b_share - a_left - b_left;
+        if dead > 0 {
+            let at = max(0, min(length, (a.progress + length - b.progress)/2));
+            clashes.push(Clash{at, time, dead});
+        }
+    }
+    for (group, lost) in groups.iter_mut().zip(lost) {
+        total_dead[group.player] += lost;
+        group.count -= lost;
+    }
+    groups.retain(|g| g.count > 0);
+}
+//a player's armies going the same way join up once they're within half a tick's travel of each other.
+//The joined army goes at the pace of the slower, from the one further back, so it never arrives sooner than either would have.
+pub fn merge_groups(groups: &mut Vec<ArmyGroup>, step: i32){
+    let mut i = 0;
+    while i < groups.len() {
+        let mut j = i+1;
+        while j < groups.len() {
+            let (a, b) = (groups[i], groups[j]);
+            if a.player == b.player && a.direction == b.direction && (a.progress - b.progress).abs()*2 < step {
+                groups[i] = ArmyGroup{progress: min(a.progress, b.progress), speed: min(a.speed, b.speed), count: a.count + b.count, ..a};
+                groups.remove(j);
+            } else {
+                j += 1;
+            }
+        }
+        i += 1;
+    }
+}
+fn isqrt(n: u64) -> u64{
+    let mut root = (n as f64).sqrt() as u64;
+    while root*root > n {
+        root -= 1;
+    }
+    while (root+1)*(root+1) <= n {
+        root += 1;
+    }
+    root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use library::ipt;
+
+    fn planet(owner: Player, counts: &[(Player, u32)]) -> Planet{
+        let mut planet = Planet::new(ipt(0, 0), owner);
+        planet.count = PlayerArr::new(0);
+        for &(p, c) in counts {
+            planet.count[p] = c;
+        }
+        planet
+    }
+    //fight until only one team is left, returns what's left and how many ticks it took
+    fn battle(mut planet: Planet, teams: &PlayerArr<Team>, config: &CombatConfig) -> (PlayerArr<u32>, u32){
+        let mut dead = PlayerArr::new(0);
+        for tick in 1..10000 {
+            fight(&mut planet, teams, config, &mut dead);
+            if find_teams(&find_sides_node(&planet), teams).len() < 2 {
+                return (planet.count, tick);
+            }
+        }
+        panic!("Battle never ended");
+    }
+
+    #[test]
+    fn square_law_is_symmetric(){
+        let teams = default_teams();
+        let config = CombatConfig::default();
+        let (a, _) = battle(planet(Player::PASSIVE, &[(Player::P1, 40), (Player::P2, 40)]), &teams, &config);
+        assert_eq!(a[Player::P1], a[Player::P2]);
+    }
+
+    #[test]
+    fn order_of_players_does_not_matter(){
+        let teams = default_teams();
+        let config = CombatConfig::default();
+        let (a, ticks_a) = battle(planet(Player::PASSIVE, &[(Player::P1, 50), (Player::P2, 30)]), &teams, &config);
+        let (b, ticks_b) = battle(planet(Player::PASSIVE, &[(Player::P1, 30), (Player::P2, 50)]), &teams, &config);
+        assert_eq!((a[Player::P1], a[Player::P2]), (b[Player::P2], b[Player::P1]));
+        assert_eq!(ticks_a, ticks_b);
+        assert!(a[Player::P1] > 0 && a[Player::P2] == 0);
+    }
+
+    #[test]
+    fn square_law_outcome(){
+        let teams = default_teams();
+        let (a, ticks) = battle(planet(Player::PASSIVE, &[(Player::P1, 50), (Player::P2, 30)]), &teams, &CombatConfig::default());
+        assert_eq!((a[Player::P1], a[Player::P2], ticks), (40, 0, 69));
+    }
+
+    #[test]
+    fn linear_law_outcome(){
+        let teams = default_teams();
+        let linear = CombatConfig{resolver: Arc::new(LinearLaw), ..CombatConfig::default()};
+        let count = PlayerArr::from_raw([0, 50, 30, 0, 0]);
+        let damage = LinearLaw.damage(&count, &teams);
+        assert_eq!(damage[Player::P1], damage[Player::P2]);
+        let (a, ticks) = battle(planet(Player::PASSIVE, &[(Player::P1, 50), (Player::P2, 30)]), &teams, &linear);
+        assert_eq!((a[Player::P1], a[Player::P2], ticks), (20, 0, 303));
+    }
+
+    #[test]
+    fn concentration_favours_the_bigger_army(){
+        let teams = default_teams();
+        let concentration = CombatConfig{resolver: Arc::new(Concentration), ..CombatConfig::default()};
+        let fights = planet(Player::PASSIVE, &[(Player::P1, 50), (Player::P2, 30)]);
+        let (plain, _) = battle(fights, &teams, &CombatConfig::default());
+        let (concentrated, ticks) = battle(fights, &teams, &concentration);
+        assert!(concentrated[Player::P1] > plain[Player::P1]);
+        assert_eq!((concentrated[Player::P1], concentrated[Player::P2], ticks), (47, 0, 42));
+    }
+
+    #[test]
+    fn even_fights_are_the_same_under_every_law(){
+        let teams = default_teams();
+        let count = PlayerArr::from_raw([0, 20, 20, 0, 0]);
+        let fights = planet(Player::PASSIVE, &[(Player::P1, 20), (Player::P2, 20)]);
+        let square = battle(fights, &teams, &CombatConfig::default());
+        for name in &["LinearLaw", "Concentration"] {
+            let resolver = named_resolver(name).unwrap();
+            assert_eq!(resolver.damage(&count, &teams), SquareLaw.damage(&count, &teams));
+            assert_eq!(battle(fights, &teams, &CombatConfig{resolver, ..CombatConfig::default()}), square);
+        }
+    }
+
+    //a rule that isn't built in: only the planet owner's team does any damage
+    #[derive(Debug)]
+    struct OwnersOnly;
+    impl CombatResolver for OwnersOnly{
+        fn name(&self) -> &'static str {"OwnersOnly"}
+        fn damage(&self, count: &PlayerArr<u32>, _teams: &PlayerArr<Team>) -> PlayerArr<u32>{
+            Player::map_from_fn(|p| if p == Player::P2 {count[p]} else {0})
+        }
+    }
+
+    #[test]
+    fn resolvers_can_be_plugged_in(){
+        let teams = default_teams();
+        let config = CombatConfig{resolver: Arc::new(OwnersOnly), ..CombatConfig::default()};
+        let (a, _) = battle(planet(Player::P2, &[(Player::P1, 50), (Player::P2, 10)]), &teams, &config);
+        assert_eq!((a[Player::P1], a[Player::P2]), (0, 10));
+    }
+
+    #[test]
+    fn rules_are_saved_by_name(){
+        let config: CombatConfig = ::toml::from_str("rule = \"LinearLaw\"\nmin_duration = 5").unwrap();
+        assert_eq!(config, CombatConfig{resolver: Arc::new(LinearLaw), min_duration: 5, ..CombatConfig::default()});
+        let saved = ::bincode::serialize(&config).unwrap();
+        assert_eq!(::bincode::deserialize::<CombatConfig>(&saved).unwrap(), config);
+        assert!(::toml::from_str::<CombatConfig>("rule = \"Simultaneous\"").is_err());
+        let custom = CombatConfig{resolver: Arc::new(OwnersOnly), ..CombatConfig::default()};
+        assert!(::bincode::deserialize::<CombatConfig>(&::bincode::serialize(&custom).unwrap()).is_err());
+    }
+
+    #[test]
+    fn defender_advantage_wins_even_fights(){
+        let teams = default_teams();
+        let config = CombatConfig{defender_advantage: 150, ..CombatConfig::default()};
+        let (a, _) = battle(planet(Player::P2, &[(Player::P1, 40), (Player::P2, 40)]), &teams, &config);
+        assert_eq!(a[Player::P1], 0);
+        assert!(a[Player::P2] > 0);
+    }
+
+    #[test]
+    fn planet_defense_stacks_with_defender_advantage(){
+        let teams = default_teams();
+        let config = CombatConfig{defender_advantage: 150, ..CombatConfig::default()};
+        let mut fortress = planet(Player::P2, &[(Player::P1, 40), (Player::P2, 40)]);
+        fortress.defense = 200;
+        let (plain, _) = battle(planet(Player::P2, &[(Player::P1, 40), (Player::P2, 40)]), &teams, &config);
+        let (walled, _) = battle(fortress, &teams, &config);
+        assert!(walled[Player::P2] > plain[Player::P2]);
+    }
+
+    #[test]
+    fn min_duration_delays_a_wipe_out(){
+        let teams = default_teams();
+        let fights = planet(Player::PASSIVE, &[(Player::P1, 1000), (Player::P2, 5)]);
+        let (_, quick) = battle(fights, &teams, &CombatConfig::default());
+        let (a, slow) = battle(fights, &teams, &CombatConfig{min_duration: 30, ..CombatConfig::default()});
+        assert!(quick < 30);
+        assert_eq!(slow, 30);
+        assert_eq!(a[Player::P2], 0);
+    }
+
+    #[test]
+    fn allies_fight_together(){
+        let mut teams = default_teams();
+        teams[Player::P2] = teams[Player::P1];
+        let (a, _) = battle(planet(Player::PASSIVE, &[(Player::P1, 25), (Player::P2, 25), (Player::P3, 40)]), &teams, &CombatConfig::default());
+        assert_eq!(a[Player::P3], 0);
+        assert!(a[Player::P1] > 0 && a[Player::P2] > 0);
+    }
+
+    #[test]
+    fn three_way_fight_is_fair(){
+        let teams = default_teams();
+        let (a, _) = battle(planet(Player::PASSIVE, &[(Player::P1, 30), (Player::P2, 30), (Player::P3, 30)]), &teams, &CombatConfig::default());
+        assert_eq!(a[Player::P1], a[Player::P2]);
+        assert_eq!(a[Player::P2], a[Player::P3]);
+    }
+
+    fn group(direction: DIR, progress: i32, count: u32, player: Player) -> ArmyGroup{
+        ArmyGroup{direction, progress, speed: 100, count, player}
+    }
+
+    #[test]
+    fn lane_armies_fight_where_they_meet(){
+        let teams = default_teams();
+        let mut groups = vec![group(DIR::FORWARD, 600, 50, Player::P1), group(DIR::BACKWARD, 500, 30, Player::P2)];
+        let (mut clashes, mut dead) = (Vec::new(), PlayerArr::new(0));
+        lane_fight(&mut groups, 1000, &teams, &SquareLaw, 7, &mut clashes, &mut dead);
+        assert_eq!(groups, vec![group(DIR::FORWARD, 600, 40, Player::P1)]);
+        assert_eq!(clashes, vec![Clash{at: 550, time: 7, dead: 40}]);
+        assert_eq!((dead[Player::P1], dead[Player::P2]), (10, 30));
+    }
+
+    #[test]
+    fn lane_armies_pass_before_meeting_and_between_allies(){
+        let mut teams = default_teams();
+        let mut groups = vec![group(DIR::FORWARD, 400, 50, Player::P1), group(DIR::BACKWARD, 500, 30, Player::P2)];
+        let (mut clashes, mut dead) = (Vec::new(), PlayerArr::new(0));
+        lane_fight(&mut groups, 1000, &teams, &SquareLaw, 7, &mut clashes, &mut dead);
+        assert_eq!(groups.len(), 2);
+        teams[Player::P2] = teams[Player::P1];
+        groups[0].progress = 600;
+        lane_fight(&mut groups, 1000, &teams, &SquareLaw, 8, &mut clashes, &mut dead);
+        assert_eq!(groups.len(), 2);
+        assert!(clashes.is_empty());
+    }
+
+    #[test]
+    fn even_lane_fight_wipes_out_both(){
+        let teams = default_teams();
+        let mut groups = vec![group(DIR::BACKWARD, 500, 20, Player::P2), group(DIR::FORWARD, 500, 20, Player::P1)];
+        let (mut clashes, mut dead) = (Vec::new(), PlayerArr::new(0));
+        lane_fight(&mut groups, 1000, &teams, &SquareLaw, 3, &mut clashes, &mut dead);
+        assert!(groups.is_empty());
+        assert_eq!(clashes[0].dead, 40);
+    }
+
+    #[test]
+    fn lane_fights_follow_the_rule(){
+        let teams = default_teams();
+        assert_eq!(LinearLaw.settle((Player::P1, 50), (Player::P2, 30), &teams), (20, 0));
+        //Concentration has no shortcut, so it's fought out the same as on a planet
+        assert_eq!(Concentration.settle((Player::P1, 50), (Player::P2, 30), &teams), (47, 0));
+        assert_eq!(OwnersOnly.settle((Player::P1, 50), (Player::P3, 30), &teams), (50, 30));
+    }
+
+    #[test]
+    fn lane_crossings_are_settled_together(){
+        let teams = default_teams();
+        let start = vec![group(DIR::FORWARD, 700, 50, Player::P1), group(DIR::BACKWARD, 400, 30, Player::P2), group(DIR::BACKWARD, 500, 20, Player::P3)];
+        let mut results = Vec::new();
+        for order in &[[0, 1, 2], [2, 1, 0], [1, 0, 2], [2, 0, 1]] {
+            let mut groups: Vec<ArmyGroup> = order.iter().map(|&i| start[i]).collect();
+            let (mut clashes, mut dead) = (Vec::new(), PlayerArr::new(0));
+            lane_fight(&mut groups, 1000, &teams, &SquareLaw, 7, &mut clashes, &mut dead);
+            groups.sort_by_key(|g| g.player as usize);
+            clashes.sort_by_key(|c| c.at);
+            results.push((groups, clashes, dead));
+        }
+        assert!(results.iter().all(|r| *r == results[0]));
+        //P1's 50 split 30/20 between the two: 30 against 30 and 20 against 20, everyone dies
+        assert_eq!(results[0].2, PlayerArr::from_raw([0, 50, 30, 20, 0]));
+        assert!(results[0].0.is_empty());
+    }
+
+    //a broken rule that gives both sides more than they had
+    #[derive(Debug)]
+    struct Reinforcing;
+    impl CombatResolver for Reinforcing{
+        fn name(&self) -> &'static str {"Reinforcing"}
+        fn damage(&self, count: &PlayerArr<u32>, _teams: &PlayerArr<Team>) -> PlayerArr<u32>{
+            *count
+        }
+        fn settle(&self, a: (Player, u32), b: (Player, u32), _teams: &PlayerArr<Team>) -> (u32, u32){
+            (a.1 + 5, b.1 + 5)
+        }
+    }
+
+    #[test]
+    fn lane_fights_never_add_units(){
+        let teams = default_teams();
+        let mut groups = vec![group(DIR::FORWARD, 600, 10, Player::P1), group(DIR::BACKWARD, 600, 4, Player::P2)];
+        let (mut clashes, mut dead) = (Vec::new(), PlayerArr::new(0));
+        lane_fight(&mut groups, 1000, &teams, &Reinforcing, 7, &mut clashes, &mut dead);
+        assert_eq!(groups, vec![group(DIR::FORWARD, 600, 10, Player::P1), group(DIR::BACKWARD, 600, 4, Player::P2)]);
+        assert!(clashes.is_empty());
+        assert_eq!(dead, PlayerArr::new(0));
+    }
+
+    #[test]
+    fn armies_merge_when_they_catch_up(){
+        let mut fast = group(DIR::FORWARD, 330, 5, Player::P1);
+        fast.speed = 150;
+        let mut groups = vec![group(DIR::FORWARD, 300, 10, Player::P1), group(DIR::FORWARD, 300, 4, Player::P2), fast, group(DIR::FORWARD, 200, 3, Player::P1)];
+        merge_groups(&mut groups, 100);
+        assert_eq!(groups, vec![group(DIR::FORWARD, 300, 15, Player::P1), group(DIR::FORWARD, 300, 4, Player::P2), group(DIR::FORWARD, 200, 3, Player::P1)]);
+    }
+}
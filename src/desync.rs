@@ -0,0 +1,94 @@
+use simulation::*;
+use timeline::*;
+use std::collections::BTreeMap;
+use std::fs;
+
+//how many of our newest hashes to send each turn, so a dropped packet doesn't leave a gap
+pub const HASHES_PER_PACKET: usize = 4;
+
+//Compares hashes of finalized worlds against the peers'. Worlds past the left edge can't change,
+//so if two peers ever disagree on one, their simulations have drifted apart.
+pub struct DesyncChecker{
+    local: BTreeMap<ChronalTime, u64>,
+    remote: PlayerArr<BTreeMap<ChronalTime, u64>>,
+    pub desync: Option<Desync>,
+    pub worlds: Vec<(Player, ChronalTime, Simulation)>, //sent by peers that disagree with us, to dump next to ours
+}
+#[derive(Copy, Clone, Debug)]
+pub struct Desync{
+    pub time: ChronalTime, //first diverging time
+    pub peer: Player,
+    pub local_hash: u64,
+    pub remote_hash: u64,
+}
+
+impl DesyncChecker{
+    pub fn new() -> DesyncChecker{
+        DesyncChecker{local: BTreeMap::new(), remote: Player::map_from_fn(|_| BTreeMap::new()), desync: None, worlds: Vec::new()}
+    }
+    //hash any newly finalized worlds, and return the newest few to send
+    pub fn record_local(&mut self, timeline: &Timeline) -> Vec<(ChronalTime, u64)>{
+        let newest = self.local.keys().next_back().cloned();
+        for time in timeline.final_times(){
+            if newest.map_or(true, |t| time > t){
+                if let Some(sim) = timeline.final_state(time){
+                    self.local.insert(time, sim.state_hash());
+                }
+            }
+        }
+        while self.local.len() > FINAL_STATE_HISTORY{
+            let oldest = *self.local.keys().next().unwrap();
+            self.local.remove(&oldest);
+        }
+        self.local.iter().rev().take(HASHES_PER_PACKET).map(|(t, h)| (*t, *h)).collect()
+    }
+    pub fn record_remote(&mut self, peer: Player, hashes: &Vec<(ChronalTime, u64)>){
+        let remote = &mut self.remote[peer];
+        for &(time, hash) in hashes{
+            remote.insert(time, hash);
+        }
+        while remote.len() > FINAL_STATE_HISTORY{
+            let oldest = *remote.keys().next().unwrap();
+            remote.remove(&oldest);
+        }
+    }
+    //returns the first disagreement with any peer, once
+    pub fn check(&mut self) -> Option<Desync>{
+        if self.desync.is_some(){
+            return None;
+        }
+        for peer in Player::values(){
+            let mut last_matched = None;
+            for (time, remote_hash) in &self.remote[peer]{
+                if let Some(local_hash) = self.local.get(time){
+                    if local_hash != remote_hash{
+                        self.desync = Some(Desync{time: *time, peer, local_hash: *local_hash, remote_hash: *remote_hash});
+                        return self.desync;
+                    }
+                    last_matched = Some(*time);
+                }
+            }
+            //anything both sides agree on doesn't need checking again
+            if let Some(last) = last_matched{
+                let remote = &mut self.remote[peer];
+                *remote = remote.split_off(&(last+1));
+            }
+        }
+        None
+    }
+}
+
+//both sides of a disagreement in one file, to diff. Ours is None if it's no longer stored.
+pub fn write_dump(local: Player, ours: Option<Simulation>, peer: Player, time: ChronalTime, theirs: &Simulation){
+    let path = format!("desync_{:?}_{:?}_{}.txt", local, peer, time);
+    let mut dump = format!("world at time {}, {:?} hash {:016x}\n", time, peer, theirs.state_hash());
+    match ours {
+        Some(sim) => dump += &format!("=== ours ({:?}), hash {:016x} ===\n{:#?}\n", local, sim.state_hash(), sim),
+        None => dump += &format!("=== ours ({:?}): no longer stored ===\n", local),
+    }
+    dump += &format!("=== theirs ({:?}) ===\n{:#?}\n", peer, theirs);
+    match fs::write(&path, dump) {
+        Ok(_) => println!("Dumped both sides of the desync to {}", path),
+        Err(e) => println!("Failed to write desync dump {}: {}", path, e),
+    }
+}
@@ -0,0 +1,65 @@
+use ggez::*;
+use ggez::graphics::*;
+use std::cmp::*;
+use std::collections::HashMap;
+
+struct Glyph{
+    text: Text,
+    width: f32,
+}
+pub struct PrerenderedFont{ //Used for drawing rapidly-changing text via a pre-rendered font
+    glyphs: HashMap<char, Glyph>,
+    pub maxw: f32,
+    pub maxh: f32
+}
+impl PrerenderedFont{
+    pub fn new(ctx: &mut Context, font: &Font, chars: &str) -> GameResult<PrerenderedFont>{
+        let mut glyphs = HashMap::new();
+        let mut maxh = 0;
+        let mut maxw = 0;
+        for c in chars.chars(){
+            let text = Text::new(ctx, &(c.to_string()), font)?;
+            maxw = maxw.max(text.width());
+            maxh = maxh.max(text.height());
+            let width = text.width() as f32;
+            let glyph = Glyph{text, width};
+            glyphs.insert(c, glyph);
+        }
+        Ok(PrerenderedFont {glyphs, maxw: maxw as f32, maxh: maxh as f32})
+    }
+    pub fn draw<S: Into<String>>(&self, ctx: &mut Context, loc: Point2, str_base: S) -> GameResult<()>{
+        let str_data = str_base.into();
+        let mut pos = loc;
+        for c in str_data.chars() {
+            let glyph = & self.glyphs[&c];
+            graphics::draw(ctx, &glyph.text, pos, 0.0)?;
+            pos += Vector2::new(glyph.width, 0.0);
+        }
+        Ok(())
+    }
+    pub fn draw_centered<S: Into<String>>(&self, ctx: &mut Context, loc: Point2, str_base: S) -> GameResult<()>{
+        let mut stack = Vec::new();
+        let str_data = str_base.into();
+        let mut total_width = 0.0;
+        for c in str_data.chars().rev() {
+            let glyph = & self.glyphs[&c];
+            stack.push(glyph);
+            total_width += glyph.width;
+        }
+        let mut pos = loc - Vector2::new(total_width/2.0, self.maxh/2.0);
+        while let Some(glyph) = stack.pop(){
+            graphics::draw(ctx, &glyph.text, pos, 0.0)?;
+            pos += Vector2::new(glyph.width, 0.0);
+        }
+        Ok(())
+    }
+    pub fn draw_centered_h<S: Into<String>>(&self, ctx: &mut Context, loc: Point2, str_base: S) -> GameResult<()>{
+        self.draw_centered(ctx,loc+Vector2::new(0.0, self.maxh/2.0),str_base)
+    }
+    pub fn get_maxw(&self) -> f32{
+        self.maxw
+    }
+    pub fn get_maxh(&self) -> f32{
+        self.maxh
+    }
+}
\ No newline at end of file
@@ -0,0 +1,165 @@
+#![allow(dead_code)]
+//Runs a map with a scripted set of commands and no window, dumping metadata for balance testing.
+//usage: headless <level.toml> <turns> [script.toml] [--json]
+extern crate chronox;
+extern crate num;
+use num::FromPrimitive;
+
+use std::env;
+use std::fs;
+use std::process;
+use std::collections::HashMap;
+
+extern crate toml;
+#[macro_use]
+extern crate serde_derive;
+
+use chronox::map_loading;
+use chronox::simulation::*;
+use chronox::timeline::*;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ScriptCommand{
+    turn: u64,
+    player: i64,
+    #[serde(rename = "type")]
+    kind: String, //transport, send_all, clear, or timejump
+    time: Option<i64>, //when the order takes effect, defaults to the present
+    from: Option<String>,
+    to: Option<String>,
+    percent: Option<i64>,
+    #[serde(skip)]
+    line: usize, //where its [[command]] starts in the script, for errors. 0 if it couldn't be found
+}
+#[derive(Serialize, Deserialize, Debug)]
+struct Script{
+    command: Vec<ScriptCommand>,
+}
+
+//the line each [[command]] starts on, if they can all be found
+fn command_lines(script: &str, commands: &mut Vec<ScriptCommand>){
+    let lines: Vec<usize> = script.lines().enumerate().filter(|&(_, l)| l.trim() == "[[command]]").map(|(i, _)| i+1).collect();
+    if lines.len() == commands.len(){
+        for (c, line) in commands.iter_mut().zip(lines){
+            c.line = line;
+        }
+    }
+}
+fn to_command(c: &ScriptCommand, ids: &HashMap<String, NodeInd>, timeline: &Timeline) -> Result<AchronalCommand, String>{
+    let player = Player::from_i64(c.player).ok_or(format!("player {} isn't 0-4", c.player))?;
+    let planet = |id: &Option<String>| -> Result<Option<NodeInd>, String> {
+        match *id {
+            Some(ref id) => ids.get(id).map(|&n| Some(n)).ok_or(format!("unknown planet {}", id)),
+            None => Ok(None),
+        }
+    };
+    //orders can only go where the timeline is
+    let time = match c.time {
+        Some(t) if t < timeline.left_edge as i64 || t >= timeline.right_edge as i64 =>
+            return Err(format!("time {} is outside the timeline on turn {} ({} to {})", t, c.turn, timeline.left_edge, timeline.right_edge - 1)),
+        Some(t) => t as ChronalTime,
+        None => timeline.present,
+    };
+    let event = match c.kind.as_str(){
+        "transport" => {
+            let to = planet(&c.to)?.ok_or("transport needs a destination")?;
+            let percent = match c.percent.unwrap_or(50) {
+                p @ 0..=100 => p as u8,
+                p => return Err(format!("percent {} isn't 0-100", p)),
+            };
+            let command = ChronalCommandTypes::Transport(TransportCommand{to, percent});
+            AchronalCommandTypes::Chronal(ChronalCommand{time, target: planet(&c.from)?, player, command})
+        }
+        "send_all" => {
+            let command = ChronalCommandTypes::SendAll(SendAllCommand{to: planet(&c.to)?});
+            AchronalCommandTypes::Chronal(ChronalCommand{time, target: planet(&c.from)?, player, command})
+        }
+        "clear" => {
+            let target = planet(&c.from)?.ok_or("clear needs a planet")?;
+            AchronalCommandTypes::ClearCommands(ClearCommand{time, target})
+        }
+        "timejump" => AchronalCommandTypes::Timejump(time),
+        other => return Err(format!("unknown command type {}", other)),
+    };
+    Ok(AchronalCommand{player, event})
+}
+
+fn print_header(json: bool){
+    if !json{
+        let mut header = "turn,time".to_string();
+        for p in Player::values(){
+            header += &format!(",{0:?}_living,{0:?}_died,{0:?}_planets", p);
+        }
+        println!("{}", header);
+    }
+}
+fn print_metadata(json: bool, turn: u64, time: ChronalTime, metadata: &SimMetadata){
+    if json{
+        let list = |arr: &PlayerArr<u32>| arr.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(",");
+        println!("{{\"turn\":{},\"time\":{},\"living\":[{}],\"died\":[{}],\"planets\":[{}]}}",
+                 turn, time, list(&metadata.total_living), list(&metadata.total_died), list(&metadata.planets_owned));
+    } else {
+        let mut row = format!("{},{}", turn, time);
+        for p in Player::values(){
+            row += &format!(",{},{},{}", metadata.total_living[p], metadata.total_died[p], metadata.planets_owned[p]);
+        }
+        println!("{}", row);
+    }
+}
+
+pub fn main() {
+    let args: Vec<String> = env::args().collect();
+    let json = args.iter().any(|a| a == "--json");
+    let positional: Vec<&String> = args.iter().skip(1).filter(|a| !a.starts_with("--")).collect();
+    if positional.len() < 2{
+        eprintln!("usage: headless <level.toml> <turns> [script.toml] [--json]");
+        return;
+    }
+    let level_data = fs::read(positional[0]).expect("Couldn't read level file");
+    let level: map_loading::LoadingMap = toml::from_slice(&level_data).expect("Couldn't parse level file");
+    let turns = positional[1].parse::<u64>().expect("Turn count (2nd arg) not a number");
+    let mut script = match positional.get(2){
+        Some(path) => {
+            let script_data = fs::read(path).expect("Couldn't read script file");
+            let mut script: Script = toml::from_slice(&script_data).expect("Couldn't parse script file");
+            command_lines(&String::from_utf8_lossy(&script_data), &mut script.command);
+            script.command
+        }
+        None => Vec::new(),
+    };
+    script.sort_by_key(|c| c.turn);
+
+    let ids = map_loading::planet_ids(&level);
+    let conditions = map_loading::load_victory(&level);
+    let teams = map_loading::load_teams(&level).expect("Bad teams");
+    let config = map_loading::load_timeline(&level).expect("Bad timeline rules");
+    let combat = map_loading::load_combat(&level).expect("Bad combat rules");
+    let mut sim = Simulation::with_teams(map_loading::load_map(level).expect("Bad level"), teams);
+    sim.combat = combat;
+    let mut timeline = Timeline::new(sim, conditions, config);
+
+    print_header(json);
+    let mut next_command = 0;
+    for turn in 1..(turns+1){
+        let mut orders = Vec::new();
+        while next_command < script.len() && script[next_command].turn <= turn{
+            let c = &script[next_command];
+            match to_command(c, &ids, &timeline) {
+                Ok(order) => orders.push(order),
+                Err(e) => {
+                    let place = if c.line > 0 {format!("line {}", c.line)} else {format!("the command for turn {}", c.turn)};
+                    eprintln!("Bad script command at {}: {}", place, e);
+                    process::exit(1);
+                }
+            }
+            next_command += 1;
+        }
+        timeline.evaluate_timestep(orders);
+        let present = timeline.present;
+        print_metadata(json, turn, present, timeline.get_metadata(present));
+        if let Some(result) = timeline.result(){
+            eprintln!("Match decided at time {} by {:?}: winners {:?}", result.time, result.condition, result.winners);
+            break;
+        }
+    }
+}
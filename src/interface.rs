@@ -1,6 +1,7 @@
 use simulation::*;
 use timeline::*;
 use library::*;
+use std::cmp::min;
 //use ggez::*;
 use ggez::event::*;
 
@@ -29,12 +30,46 @@ pub struct InterfaceConfig{
     pub width: i32,
     pub height: i32,
 }
+//the last thing a player saw on a planet that's now hidden
+#[derive(Copy, Clone, Debug)]
+pub struct LastSeen{
+    pub owner: Player,
+    pub count: PlayerArr<u32>,
+    pub time: ChronalTime,
+}
+pub struct Fog{
+    pub visible: Visibility,
+    pub last_seen: Vec<Option<LastSeen>>,
+}
+impl Fog{
+    //last known state of a hidden planet, if it was seen at or before the time being viewed
+    pub fn remembered(&self, node: NodeInd, time: ChronalTime) -> Option<LastSeen>{
+        match self.last_seen[node.index()] {
+            Some(seen) if seen.time <= time => Some(seen),
+            _ => None,
+        }
+    }
+}
 pub struct GameInterface{
     pub selected: Option<NodeInd>,
     pub center_loc: Vector2,
     pub send_percent: i32,
+    pub fog: Option<Fog>, //None if the map has no fog of war
+    pub overlay: bool, //every player's timewave and chrono energy, for watching
+    pub preview: Option<ChronalTime>, //the time under the mouse on the timeline, shown without jumping there
     keyboard: KeyboardStates,
 }
+//the time at this point on the timeline, if it's on the timeline
+fn timeline_time(pt: Ipt, timeline: &Timeline, conf: &InterfaceConfig) -> Option<ChronalTime>{
+    if pt.y <= (conf.height-conf.ui_height) {
+        return None;
+    }
+    let width = conf.width as f32;
+    let x_pos = (pt.x as f32)/width;
+    let time = x_pos*((timeline.right_edge - timeline.left_edge) as f32);
+    let time_to = (time.max(0.) as ChronalTime)+timeline.left_edge;
+    Some(min(time_to, timeline.right_edge-1))
+}
 fn add_order(order: AchronalCommand, orders: &mut CommandBuffer){
     orders.back_mut().unwrap().push(order);
 
@@ -45,8 +80,37 @@ fn chronal_event(command: ChronalCommand, player: Player, orders: &mut CommandBu
     add_order(order, orders);
 }
 impl GameInterface {
-    pub fn new() -> GameInterface {
-        GameInterface { selected: None, center_loc: Vector2::new(0., 0.), keyboard: KeyboardStates::new(false), send_percent: 50 }
+    pub fn new(fog_of_war: bool) -> GameInterface {
+        let fog = if fog_of_war {
+            Some(Fog{visible: Visibility{planets: Vec::new(), lanes: Vec::new()}, last_seen: Vec::new()})
+        } else {
+            None
+        };
+        GameInterface { selected: None, center_loc: Vector2::new(0., 0.), keyboard: KeyboardStates::new(false), send_percent: 50, fog, overlay: false, preview: None }
+    }
+    //recompute what's visible in the world being viewed, remembering anything seen
+    pub fn update_fog(&mut self, sim: &Simulation, player: Player) {
+        if let Some(ref mut fog) = self.fog {
+            fog.visible = sim.visibility(player);
+            fog.last_seen.resize(sim.world.node_count(), None);
+            for node_ind in sim.world.node_indices() {
+                if fog.visible.planet(node_ind) {
+                    let node = &sim.world[node_ind];
+                    fog.last_seen[node_ind.index()] = Some(LastSeen{owner: node.owner, count: node.count, time: sim.timestep});
+                }
+            }
+            if let Some(selected) = self.selected {
+                if !fog.visible.planet(selected) {
+                    self.selected = None;//lost sight of it
+                }
+            }
+        }
+    }
+    pub fn can_see(&self, node: NodeInd) -> bool {
+        match self.fog {
+            Some(ref fog) => fog.visible.planet(node),
+            None => true,
+        }
     }
 
     pub fn update(&mut self, conf: &InterfaceConfig) {
@@ -65,11 +129,8 @@ impl GameInterface {
     }
     pub fn mouse_up(&mut self, button: MouseButton, pt: Ipt, player: Player, timeline: &Timeline, orders: &mut CommandBuffer, conf: &InterfaceConfig) {
         let sim = &timeline[player];
-        if pt.y > (conf.height-conf.ui_height) {
-            let width = conf.width as f32;
-            let x_pos = (pt.x as f32)/width;
-            let time = x_pos*((timeline.right_edge - timeline.left_edge) as f32);
-            let time_to = (time as ChronalTime)+timeline.left_edge;
+        if let Some(time_to) = timeline_time(pt, timeline, conf) {
+            //only now does it become a jump
             let event = AchronalCommandTypes::Timejump(time_to);
             let command = AchronalCommand{event, player};
             add_order(command, orders);
@@ -82,7 +143,7 @@ impl GameInterface {
                         let next_o = sim.check_planets(world_pt, 96);
                         if let Some(next) = next_o {
                             if next != selected {
-                                if sim.world.contains_edge(selected, next) {
+                                if sim.can_send(selected, next) {
                                     let transport = TransportCommand { to: next, percent: (self.send_percent as u8) };
                                     let command = ChronalCommandTypes::Transport(transport);
                                     let event = ChronalCommand{time: sim.timestep+(orders.len() as ChronalTime), target: self.selected, player, command};
@@ -96,7 +157,7 @@ impl GameInterface {
                         let mut send_all = SendAllCommand {to: None };
                         if let Some(next) = next_o {
                             if next != selected {
-                                if sim.world.contains_edge(selected, next) {
+                                if sim.can_send(selected, next) {
                                     send_all.to = Some(next);
                                 }
                             }
@@ -118,7 +179,8 @@ impl GameInterface {
         } else {
             if button == MouseButton::Left || button == MouseButton::Right {
                 let world_pt = pt + na::Vector2::new(self.center_loc.x.round() as i32, self.center_loc.y.round() as i32);
-                let selection = sim.check_planets(world_pt, 96);
+                //orders can only come from planets you can see, but can be sent blind into the fog
+                let selection = sim.check_planets(world_pt, 96).filter(|&n| self.can_see(n));
                 if let Some(prev_selected) = self.selected {
                     if let Some(this_selection) = selection{
                         if this_selection == prev_selected{
@@ -135,10 +197,11 @@ impl GameInterface {
             }
         }
     }
-    pub fn mouse_move(&mut self, state: MouseState, rel: Vector2){
+    pub fn mouse_move(&mut self, state: MouseState, pt: Ipt, rel: Vector2, timeline: &Timeline, conf: &InterfaceConfig){
         if state.middle() {
             self.center_loc -= rel;
         }
+        self.preview = timeline_time(pt, timeline, conf);
     }
     pub fn mouse_wheel(&mut self, amount: i32){
         self.send_percent += amount*10;
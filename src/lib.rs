@@ -0,0 +1,53 @@
+//! Chronox's rules engine: the deterministic simulation, the timeline built on top of it,
+//! orders, map loading and the lockstep networking. None of this needs a graphics context.
+//! The ggez client (rendering and input) is only built with the `client` feature.
+#![allow(dead_code)]
+/*extern crate rand;
+use rand::distributions::{IndependentSample, Range};
+use rand::{thread_rng, Rng};*/
+
+//This has been replaced with a local copy to add
+#[macro_use]
+pub mod plain_enum;
+//extern crate plain_enum;
+extern crate num;
+#[macro_use]
+extern crate num_derive;
+
+pub extern crate nalgebra;
+extern crate petgraph;
+extern crate toml;
+extern crate bincode;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "client")]
+extern crate ggez;
+
+pub mod library;
+pub mod orders;
+pub mod simulation;
+pub mod combat;
+pub mod timeline;
+pub mod victory;
+pub mod map_loading;
+pub mod transport;
+pub mod networking;
+pub mod lobby;
+pub mod relay;
+pub mod desync;
+pub mod replay;
+pub mod save;
+
+#[cfg(feature = "client")]
+pub mod font;
+#[cfg(feature = "client")]
+pub mod renderer;
+#[cfg(feature = "client")]
+pub mod interface;
+
+pub use simulation::{Simulation, SimMetadata, WorldGraph, Planet, HyperLane, ArmyGroup, Player, PlayerArr, NodeInd};
+pub use orders::*;
+pub use timeline::Timeline;
+pub use victory::{VictoryCondition, MatchResult};
+pub use map_loading::LoadingMap;
@@ -1,10 +1,8 @@
-use ggez::*;
-use ggez::graphics::*;
-pub use ggez::graphics::{Point2, Vector2};
-pub use ggez::nalgebra as na;
-use std::cmp::*;
-use std::collections::HashMap;
+pub use nalgebra as na;
+pub type Point2 = na::Point2<f32>;
+pub type Vector2 = na::Vector2<f32>;
 use num::Num;
+use std::hash::Hasher;
 pub use std::f32::consts::PI as PI;
 
 pub type Ipt = na::Point2<i32>;
@@ -52,63 +50,40 @@ pub fn bound<T: PartialOrd>(val: T, min: T, max: T) -> T{
     return val;
 }
 
-struct Glyph{
-    text: Text,
-    width: f32,
-}
-pub struct PrerenderedFont{ //Used for drawing rapidly-changing text via a pre-rendered font
-    glyphs: HashMap<char, Glyph>,
-    pub maxw: f32,
-    pub maxh: f32
+//FNV-1a. Unlike the std hasher, this is guaranteed to give the same result on every machine and build,
+//so it can be compared across the network.
+pub struct Fnv64(u64);
+impl Fnv64{
+    pub fn new() -> Fnv64{
+        Fnv64(0xcbf29ce484222325)
+    }
 }
-impl PrerenderedFont{
-    pub fn new(ctx: &mut Context, font: &Font, chars: &str) -> GameResult<PrerenderedFont>{
-        let mut glyphs = HashMap::new();
-        let mut maxh = 0;
-        let mut maxw = 0;
-        for c in chars.chars(){
-            let text = Text::new(ctx, &(c.to_string()), font)?;
-            maxw = maxw.max(text.width());
-            maxh = maxh.max(text.height());
-            let width = text.width() as f32;
-            let glyph = Glyph{text, width};
-            glyphs.insert(c, glyph);
-        }
-        Ok(PrerenderedFont {glyphs, maxw: maxw as f32, maxh: maxh as f32})
+impl Hasher for Fnv64{
+    fn finish(&self) -> u64{
+        self.0
     }
-    pub fn draw<S: Into<String>>(&self, ctx: &mut Context, loc: Point2, str_base: S) -> GameResult<()>{
-        let str_data = str_base.into();
-        let mut pos = loc;
-        for c in str_data.chars() {
-            let glyph = & self.glyphs[&c];
-            graphics::draw(ctx, &glyph.text, pos, 0.0)?;
-            pos += Vector2::new(glyph.width, 0.0);
+    fn write(&mut self, bytes: &[u8]){
+        for b in bytes{
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
         }
-        Ok(())
     }
-    pub fn draw_centered<S: Into<String>>(&self, ctx: &mut Context, loc: Point2, str_base: S) -> GameResult<()>{
-        let mut stack = Vec::new();
-        let str_data = str_base.into();
-        let mut total_width = 0.0;
-        for c in str_data.chars().rev() {
-            let glyph = & self.glyphs[&c];
-            stack.push(glyph);
-            total_width += glyph.width;
-        }
-        let mut pos = loc - Vector2::new(total_width/2.0, self.maxh/2.0);
-        while let Some(glyph) = stack.pop(){
-            graphics::draw(ctx, &glyph.text, pos, 0.0)?;
-            pos += Vector2::new(glyph.width, 0.0);
-        }
-        Ok(())
+    //fixed width and byte order regardless of platform
+    fn write_usize(&mut self, i: usize){
+        self.write_u64(i as u64);
+    }
+    fn write_u16(&mut self, i: u16){
+        self.write(&[i as u8, (i >> 8) as u8]);
     }
-    pub fn draw_centered_h<S: Into<String>>(&self, ctx: &mut Context, loc: Point2, str_base: S) -> GameResult<()>{
-        self.draw_centered(ctx,loc+Vector2::new(0.0, self.maxh/2.0),str_base)
+    fn write_u32(&mut self, i: u32){
+        self.write_u16(i as u16);
+        self.write_u16((i >> 16) as u16);
     }
-    pub fn get_maxw(&self) -> f32{
-        self.maxw
+    fn write_u64(&mut self, i: u64){
+        self.write_u32(i as u32);
+        self.write_u32((i >> 32) as u32);
     }
-    pub fn get_maxh(&self) -> f32{
-        self.maxh
+    fn write_i32(&mut self, i: i32){
+        self.write_u32(i as u32);
     }
-}
\ No newline at end of file
+}
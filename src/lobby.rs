@@ -0,0 +1,424 @@
+use simulation::*;
+use networking::*;
+use timeline::TimelineConfig;
+use map_loading;
+use library::Fnv64;
+use std::hash::Hasher;
+use std::io;
+use std::io::ErrorKind;
+use std::net::*;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use bincode::*;
+
+//bump whenever lobby or game packets change
+pub const LOBBY_PROTOCOL_VERSION: u32 = 5;
+const RESEND_MS: u64 = 250;
+pub const COUNTDOWN_MS: u64 = 3000;
+const LOBBY_PACKET_LIMIT: u64 = 4096;
+
+//hash of the source this was built from, set by build.rs. Builds that differ could simulate differently.
+pub fn build_hash() -> u64{
+    u64::from_str_radix(env!("CHRONOX_BUILD_HASH"), 16).unwrap()
+}
+pub fn map_hash(data: &[u8]) -> u64{
+    let mut h = Fnv64::new();
+    h.write(data);
+    h.finish()
+}
+
+//everything the host decides for everyone
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GameSettings{
+    pub level: String,
+    pub map_hash: u64,
+    pub tick_time: u32,
+    pub command_delay: usize,
+    pub teams: Option<Vec<i64>>,
+    pub timeline: Option<TimelineConfig>,
+}
+impl GameSettings{
+    //what's wrong with the settings, if anything. Joining players check them before using them.
+    pub fn problem(&self) -> Option<String>{
+        if self.command_delay < MIN_COMMAND_DELAY || self.command_delay > MAX_COMMAND_DELAY{
+            return Some(format!("command_delay is {}, it must be from {} to {}", self.command_delay, MIN_COMMAND_DELAY, MAX_COMMAND_DELAY));
+        }
+        if self.tick_time == 0{
+            return Some("tick_time must be at least 1".to_string());
+        }
+        if let Some(ref teams) = self.teams{
+            if let Err(problem) = map_loading::teams_from_list(teams){
+                return Some(format!("Bad teams: {}", problem));
+            }
+        }
+        if let Some(problem) = self.timeline.as_ref().and_then(|t| t.problem()){
+            return Some(format!("Bad timeline rules: {}", problem));
+        }
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum LobbyMessage{
+    Hello{protocol: u32, build: u64, spectator: bool},
+    Welcome{player: Player, settings: GameSettings},
+    Ready{map_hash: u64},
+    Reject{reason: String},
+    //None is the host, everyone else is as the host sees them. A PASSIVE host is a dedicated server, and the only peer.
+    //Spectators are PASSIVE too, and only ever watch a dedicated server.
+    Start{peers: Vec<(Player, Option<String>)>, start_in_ms: u64},
+}
+
+pub enum LobbyEvent{
+    Waiting,
+    LoadMap(Player, GameSettings), //joined, load the level and call map_loaded
+    Failed(String),
+    Start, //countdown is over, call finish
+}
+
+pub struct LobbyPeer{
+    pub player: Player,
+    pub addr: SocketAddrV4,
+    pub connected: bool, //we've already heard from them in the lobby
+    pub relay: bool, //a dedicated server, which relays everyone's commands
+}
+//what the game needs from the lobby
+pub struct LobbyResult{
+    pub sock: UdpSocket,
+    pub player: Player,
+    pub peers: Vec<LobbyPeer>,
+    pub settings: GameSettings,
+}
+
+pub fn send_lobby(sock: &UdpSocket, player: Player, addr: SocketAddrV4, message: &LobbyMessage){
+    let mut buf = vec![PACKET_LOBBY, player as u8];
+    serialize_into(&mut buf, message).unwrap();
+    if let Err(e) = sock.send_to(&buf, addr){
+        println!("Sending to {} failed: {}", addr, e);
+    }
+}
+//a lobby packet, without the packet type and player
+pub fn parse_lobby(data: &[u8]) -> Result<LobbyMessage>{
+    config().limit(LOBBY_PACKET_LIMIT).deserialize(data)
+}
+//why someone saying hello can't play with us, if they can't
+pub fn hello_problem(protocol: u32, build: u64) -> Option<String>{
+    if protocol != LOBBY_PROTOCOL_VERSION{
+        Some(format!("Host uses lobby protocol {}, you use {}. Update the game.", LOBBY_PROTOCOL_VERSION, protocol))
+    } else if build != build_hash(){
+        Some(format!("Host's game build {:016x} doesn't match yours ({:016x}). Both players need the same version.", build_hash(), build))
+    } else {
+        None
+    }
+}
+//next lobby message waiting on the socket, skipping anything else
+fn receive(sock: &UdpSocket) -> Option<(LobbyMessage, SocketAddrV4)>{
+    let mut buf = [0; LOBBY_PACKET_LIMIT as usize];
+    loop {
+        match sock.recv_from(&mut buf) {
+            Ok((number_of_bytes, SocketAddr::V4(src_addr))) => {
+                if number_of_bytes < 2 || buf[0] != PACKET_LOBBY{
+                    continue;//game packets from a peer that started first
+                }
+                match parse_lobby(&buf[2..number_of_bytes]){
+                    Ok(message) => return Some((message, src_addr)),
+                    Err(e) => println!("Bad lobby packet from {}: {}", src_addr, e),
+                }
+            }
+            Ok((_, src_addr)) => println!("Packet received from ipv6 {}, discarding", src_addr),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return None,
+            Err(e) => println!("encountered IO error: {}", e),
+        }
+    }
+}
+fn millis(d: Duration) -> u64{
+    d.as_secs()*1000 + (d.subsec_nanos()/1_000_000) as u64
+}
+fn countdown_status(start_at: Instant) -> String{
+    let now = Instant::now();
+    let left = if start_at > now {millis(start_at - now)} else {0};
+    format!("Starting in {:.1}s", (left as f32)/1000.0)
+}
+
+struct Slot{
+    player: Player, //PASSIVE for a spectator
+    addr: SocketAddrV4,
+    ready: bool,
+}
+//The host is P1, or PASSIVE for a dedicated server. It hands out the rest of the map's starting slots in order.
+//A dedicated server also takes any number of spectators, who don't hold up the start.
+pub struct LobbyHost{
+    sock: UdpSocket,
+    host: Player,
+    settings: GameSettings,
+    open: Vec<Player>,
+    slots: Vec<Slot>,
+    last_sent: Instant,
+    start_at: Option<Instant>,
+}
+impl LobbyHost{
+    pub fn new(settings: GameSettings, players: &Vec<Player>, port: u16, host: Player) -> io::Result<LobbyHost>{
+        if let Some(problem) = settings.problem(){
+            return Err(io::Error::new(ErrorKind::InvalidInput, problem));
+        }
+        let sock = UdpSocket::bind(&format!("0.0.0.0:{}", port))?;
+        sock.set_nonblocking(true)?;
+        let open = players.iter().cloned().filter(|&p| p != host).collect();
+        Ok(LobbyHost{sock, host, settings, open, slots: Vec::new(), last_sent: Instant::now(), start_at: None})
+    }
+    fn reject(&self, addr: SocketAddrV4, reason: String){
+        println!("Rejected {}: {}", addr, reason);
+        send_lobby(&self.sock, self.host, addr, &LobbyMessage::Reject{reason});
+    }
+    fn start_message(&self) -> LobbyMessage{
+        let mut peers = vec![(self.host, None)];
+        if self.host != Player::PASSIVE{
+            for slot in &self.slots{
+                peers.push((slot.player, Some(slot.addr.to_string())));
+            }
+        }
+        let now = Instant::now();
+        let start_at = self.start_at.unwrap();
+        let start_in_ms = if start_at > now {millis(start_at - now)} else {0};
+        LobbyMessage::Start{peers, start_in_ms}
+    }
+    pub fn update(&mut self) -> LobbyEvent{
+        while let Some((message, addr)) = receive(&self.sock){
+            let slot = self.slots.iter().position(|s| s.addr == addr);
+            match message{
+                LobbyMessage::Hello{protocol, build, spectator} => {
+                    if let Some(problem) = hello_problem(protocol, build){
+                        self.reject(addr, problem);
+                    } else if let Some(i) = slot{
+                        //they missed the welcome
+                        let welcome = LobbyMessage::Welcome{player: self.slots[i].player, settings: self.settings.clone()};
+                        send_lobby(&self.sock, self.host, addr, &welcome);
+                    } else if spectator && self.host != Player::PASSIVE{
+                        self.reject(addr, "Only a dedicated server can take spectators".to_string());
+                    } else if self.start_at.is_some(){
+                        self.reject(addr, "The game has already started".to_string());
+                    } else if spectator{
+                        println!("{} joined as a spectator", addr);
+                        self.slots.push(Slot{player: Player::PASSIVE, addr, ready: false});
+                        send_lobby(&self.sock, self.host, addr, &LobbyMessage::Welcome{player: Player::PASSIVE, settings: self.settings.clone()});
+                    } else if self.open.is_empty(){
+                        self.reject(addr, "The game is full".to_string());
+                    } else {
+                        let player = self.open.remove(0);
+                        println!("{} joined as {:?}", addr, player);
+                        self.slots.push(Slot{player, addr, ready: false});
+                        send_lobby(&self.sock, self.host, addr, &LobbyMessage::Welcome{player, settings: self.settings.clone()});
+                    }
+                }
+                LobbyMessage::Ready{map_hash} => {
+                    if let Some(i) = slot{
+                        if map_hash != self.settings.map_hash{
+                            self.reject(addr, format!("Your copy of {} doesn't match the host's", self.settings.level));
+                            let player = self.slots.remove(i).player;
+                            if player != Player::PASSIVE{
+                                self.open.push(player);
+                                self.open.sort();
+                            }
+                        } else if !self.slots[i].ready{
+                            println!("{} ({:?}) is ready", addr, self.slots[i].player);
+                            self.slots[i].ready = true;
+                        }
+                    }
+                }
+                _ => {},
+            }
+        }
+        if self.start_at.is_none() && self.open.is_empty() && self.slots.iter().all(|s| s.ready || s.player == Player::PASSIVE){
+            println!("Everyone's ready, starting in {}ms", COUNTDOWN_MS);
+            self.start_at = Some(Instant::now() + Duration::from_millis(COUNTDOWN_MS));
+            self.last_sent = Instant::now() - Duration::from_millis(RESEND_MS);
+        }
+        //resend until it's too late to matter
+        if Instant::now() - self.last_sent >= Duration::from_millis(RESEND_MS){
+            self.last_sent = Instant::now();
+            if self.start_at.is_some(){
+                let start = self.start_message();
+                for slot in &self.slots{
+                    send_lobby(&self.sock, self.host, slot.addr, &start);
+                }
+            }
+        }
+        match self.start_at{
+            Some(start_at) if Instant::now() >= start_at => LobbyEvent::Start,
+            _ => LobbyEvent::Waiting,
+        }
+    }
+    pub fn status(&self) -> Vec<String>{
+        let mut lines = vec![format!("Hosting {} on port {}", self.settings.level, self.sock.local_addr().map(|a| a.port()).unwrap_or(0))];
+        if self.host != Player::PASSIVE{
+            lines.push(format!("{:?}: you", self.host));
+        }
+        for slot in &self.slots{
+            let name = if slot.player == Player::PASSIVE {"Spectator".to_string()} else {format!("{:?}", slot.player)};
+            lines.push(format!("{}: {} {}", name, slot.addr, if slot.ready {"ready"} else {"loading"}));
+        }
+        match self.start_at{
+            Some(start_at) => lines.push(countdown_status(start_at)),
+            None => lines.push(format!("Waiting for {} more players", self.open.len())),
+        }
+        lines
+    }
+    pub fn finish(self) -> LobbyResult{
+        let peers = self.slots.iter().map(|s| LobbyPeer{player: s.player, addr: s.addr, connected: true, relay: false}).collect();
+        LobbyResult{sock: self.sock, player: self.host, peers, settings: self.settings}
+    }
+}
+
+pub struct LobbyClient{
+    sock: UdpSocket,
+    host: SocketAddrV4,
+    joined: Option<(Player, GameSettings)>,
+    map_hash: Option<u64>,
+    last_sent: Instant,
+    rtt: Duration, //to the host, to line up the countdown
+    start: Option<(Instant, Vec<LobbyPeer>)>,
+    failed: Option<String>,
+    spectator: bool,
+}
+impl LobbyClient{
+    //host is "ip" or "ip:port", defaulting to default_port. A spectator only watches.
+    pub fn new(host: &str, default_port: u16, spectator: bool) -> io::Result<LobbyClient>{
+        let full = if host.contains(':') {host.to_string()} else {format!("{}:{}", host, default_port)};
+        let host = SocketAddrV4::from_str(&full).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        let sock = UdpSocket::bind("0.0.0.0:0")?;
+        sock.set_nonblocking(true)?;
+        let last_sent = Instant::now() - Duration::from_millis(RESEND_MS);
+        Ok(LobbyClient{sock, host, joined: None, map_hash: None, last_sent, rtt: Duration::from_millis(0), start: None, failed: None, spectator})
+    }
+    fn player(&self) -> Player{
+        self.joined.as_ref().map_or(Player::PASSIVE, |j| j.0)
+    }
+    //the hash of our copy of the host's level, or why we couldn't start it
+    pub fn map_loaded(&mut self, loaded: ::std::result::Result<u64, String>){
+        self.map_hash = Some(*loaded.as_ref().unwrap_or(&0));
+        self.last_sent = Instant::now() - Duration::from_millis(RESEND_MS);
+        if let Err(reason) = loaded{
+            self.failed = Some(reason);
+        }
+    }
+    fn parse_peers(&self, peers: Vec<(Player, Option<String>)>) -> Option<Vec<LobbyPeer>>{
+        let mut parsed = Vec::new();
+        for (player, addr) in peers{
+            if addr.is_some() && player == self.player(){
+                continue;
+            }
+            let peer = match addr{
+                None => LobbyPeer{player, addr: self.host, connected: true, relay: player == Player::PASSIVE},
+                Some(addr) => LobbyPeer{player, addr: SocketAddrV4::from_str(&addr).ok()?, connected: false, relay: false},
+            };
+            parsed.push(peer);
+        }
+        Some(parsed)
+    }
+    pub fn update(&mut self) -> LobbyEvent{
+        let mut event = LobbyEvent::Waiting;
+        while let Some((message, addr)) = receive(&self.sock){
+            if addr != self.host{
+                continue;
+            }
+            match message{
+                LobbyMessage::Welcome{player, settings} => {
+                    if self.joined.is_none(){
+                        if let Some(problem) = settings.problem(){
+                            self.failed = Some(format!("The host's settings won't work. {}", problem));
+                        } else {
+                            self.rtt = Instant::now() - self.last_sent;
+                            println!("Joined as {:?}, playing {}", player, settings.level);
+                            self.joined = Some((player, settings.clone()));
+                            event = LobbyEvent::LoadMap(player, settings);
+                        }
+                    }
+                }
+                LobbyMessage::Reject{reason} => {
+                    self.failed = Some(reason);
+                }
+                LobbyMessage::Start{peers, start_in_ms} => {
+                    if self.start.is_none() && self.map_hash.is_some(){
+                        match self.parse_peers(peers){
+                            Some(peers) => {
+                                //the host sent this half a round trip ago
+                                let start_at = Instant::now() + Duration::from_millis(start_in_ms) - self.rtt/2;
+                                self.start = Some((start_at, peers));
+                            }
+                            None => self.failed = Some("Host sent a bad peer address".to_string()),
+                        }
+                    }
+                }
+                _ => {},
+            }
+        }
+        if let Some(ref reason) = self.failed{
+            return LobbyEvent::Failed(reason.clone());
+        }
+        if Instant::now() - self.last_sent >= Duration::from_millis(RESEND_MS){
+            self.last_sent = Instant::now();
+            if self.joined.is_none(){
+                send_lobby(&self.sock, Player::PASSIVE, self.host, &LobbyMessage::Hello{protocol: LOBBY_PROTOCOL_VERSION, build: build_hash(), spectator: self.spectator});
+            } else if let (Some(map_hash), None) = (self.map_hash, self.start.as_ref()){
+                send_lobby(&self.sock, self.player(), self.host, &LobbyMessage::Ready{map_hash});
+            }
+        }
+        match self.start{
+            Some((start_at, _)) if Instant::now() >= start_at => LobbyEvent::Start,
+            _ => event,
+        }
+    }
+    pub fn status(&self) -> Vec<String>{
+        let mut lines = vec![format!("Joining {}", self.host)];
+        match self.joined{
+            None => lines.push("Waiting for the host...".to_string()),
+            Some((player, ref settings)) => {
+                if self.spectator{
+                    lines.push(format!("Spectating {}", settings.level));
+                } else {
+                    lines.push(format!("Joined as {:?}, playing {}", player, settings.level));
+                }
+                match self.start{
+                    Some((start_at, _)) => lines.push(countdown_status(start_at)),
+                    None => lines.push("Waiting for the other players".to_string()),
+                }
+            }
+        }
+        lines
+    }
+    pub fn finish(self) -> LobbyResult{
+        let (player, settings) = self.joined.unwrap();
+        let (_, peers) = self.start.unwrap();
+        LobbyResult{sock: self.sock, player, peers, settings}
+    }
+}
+
+pub enum Lobby{
+    Host(LobbyHost),
+    Client(LobbyClient),
+}
+impl Lobby{
+    pub fn update(&mut self) -> LobbyEvent{
+        match *self{
+            Lobby::Host(ref mut host) => host.update(),
+            Lobby::Client(ref mut client) => client.update(),
+        }
+    }
+    pub fn status(&self) -> Vec<String>{
+        match *self{
+            Lobby::Host(ref host) => host.status(),
+            Lobby::Client(ref client) => client.status(),
+        }
+    }
+    pub fn map_loaded(&mut self, loaded: ::std::result::Result<u64, String>){
+        if let Lobby::Client(ref mut client) = *self{
+            client.map_loaded(loaded);
+        }
+    }
+    pub fn finish(self) -> LobbyResult{
+        match self{
+            Lobby::Host(host) => host.finish(),
+            Lobby::Client(client) => client.finish(),
+        }
+    }
+}
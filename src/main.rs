@@ -1,16 +1,6 @@
 #![allow(dead_code)]
-/*extern crate rand;
-use rand::distributions::{IndependentSample, Range};
-use rand::{thread_rng, Rng};*/
-
-//This has been replaced with a local copy to add
-#[macro_use]
-mod plain_enum;
-//extern crate plain_enum;
+extern crate chronox;
 extern crate num;
-#[macro_use]
-extern crate num_derive;
-use num::FromPrimitive;
 
 use std::env;
 use std::path;
@@ -22,25 +12,20 @@ extern crate ggez;
 use ggez::*;
 use ggez::event::*;
 
-extern crate petgraph;
-
-mod map_loading;
-mod orders;
-mod networking;
-use networking::*;
-mod library;
-use library::*;
-mod simulation;
-use simulation::*;
-mod renderer;
-use renderer::*;
-mod interface;
-use interface::*;
-mod timeline;
-use timeline::*;
+use chronox::map_loading;
+use chronox::networking::*;
+use chronox::library::*;
+use chronox::simulation::*;
+use chronox::renderer::*;
+use chronox::interface::*;
+use chronox::timeline::*;
+use chronox::victory::*;
+use chronox::replay::*;
+use chronox::save;
+use chronox::lobby::*;
+use chronox::desync::write_dump;
 
 extern crate toml;
-extern crate bincode;
 #[macro_use]
 extern crate serde_derive;
 
@@ -50,6 +35,7 @@ extern crate serde_derive;
 struct Config{
     system: SystemConfig,
     interface: InterfaceConfig,
+    timeline: Option<TimelineConfig>, //overrides the level's timeline rules when hosting
 }
 use std::default::Default;
 impl Default for Config{
@@ -58,7 +44,11 @@ impl Default for Config{
             tick_time: 100,
             command_delay: 4,
             port_from: Some(40004),
-            port_to: Some(40004)
+            port_to: Some(40004),
+            replay_file: Some("last_replay.bin".to_string()),
+            save_file: Some("quicksave.bin".to_string()),
+            level: Some("/level1.toml".to_string()),
+            teams: None,
         };
         let interface = InterfaceConfig{
             scroll_speed: 2.0,
@@ -67,13 +57,20 @@ impl Default for Config{
             width: 1200, height: 700
         };
         Config{
-            system, interface,
+            system, interface, timeline: None,
         }
     }
 }
+//how often to check whether the command delay should change
+const DELAY_CHECK_TURNS: u64 = 20;
+//most turns to play in one frame when behind, e.g. after rejoining
+const CATCH_UP_TURNS_PER_FRAME: u32 = 100;
 enum MenuState{
+    Lobby,
+    LobbyFailed(String),
     WaitingForConnection,
     Playing,
+    Results(MatchResult),
 }
 struct MainState {
     timeline: Timeline,
@@ -83,10 +80,17 @@ struct MainState {
     conf: Config,
 
     orders: CommandBuffer,
+    command_delay: usize,
+    wanted_delay: PlayerArr<Option<usize>>, //the latest delay each player asked for
     player: Player,
+    recording: Replay,
+    playback: Option<Playback>,
+    lobby: Option<Lobby>,
+    spectating: bool, //watching someone else's game live, player is who we're watching
 
     frame: u64,
     turn: u64,
+    caught_up: u32, //turns played early this frame, to catch up
     residual_update_dt: time::Duration,
     last_instant: time::Instant,
     last_turn: time::Instant,
@@ -102,49 +106,131 @@ impl MainState {
         if conf_data.is_err(){
             println!("Config file failed to load, loading default.");
         }
-        let conf: Config = conf_data.unwrap_or_default();
-
-        let mut level_file = ctx.filesystem.open("/level1.toml")?;
-        let mut buffer_l = Vec::new();
-        level_file.read_to_end(&mut buffer_l)?;
-        let level: map_loading::LoadingMap = toml::from_slice(&buffer_l).unwrap();
-        let graph = map_loading::load_map(level);
-        let sim = Simulation::new(graph);
-        let timeline = Timeline::new(sim);
-        let renderer = Renderer::new(ctx)?;
-        let mut orders = VecDeque::new();
-        for _ in 0..conf.system.command_delay{
-            orders.push_front(Vec::new());
-        }
-
+        let mut conf: Config = conf_data.unwrap_or_default();
 
         let args: Vec<String> = env::args().collect();
         println!("args: {:?}", args);
-        let player;
-        if let Some(player_str) = args.get(1) {
-            let player_num = player_str.parse::<i64>().expect("Player ID (1st arg) not a number");
-            player = Player::from_i64(player_num).expect("Player ID (1st arg) not 1-4");
-            //Have fun with player 0!
+        let mode = args.get(1).map(|s| s.as_str());
+        let playback = if mode == Some("--replay") {
+            let path = args.get(2).expect("--replay needs a file");
+            let replay = Replay::load(path).expect("Replay failed to load");
+            conf.system.tick_time = replay.tick_time;//play it back at the pace it was recorded
+            Some(Playback::new(replay))
         } else {
-            player = Player::P1;
-        }
-        let ipaddr = args.get(2).cloned();
-        let state = match ipaddr.is_some(){
-            true => MenuState::WaitingForConnection,
-            false => MenuState::Playing,
+            None
         };
-        let networking = match ipaddr{
-            Some(ip) => Some(NetworkManager::new(&ip, &conf.system)),
-            None => None,
+
+        let level_path = conf.system.level.clone().unwrap_or("/level1.toml".to_string());
+        let (level, level_data) = match playback {
+            Some(ref p) => (p.replay.level.clone(), Vec::new()),
+            None => {
+                let level_data = read_level(ctx, &level_path)?;
+                (toml::from_slice(&level_data).expect("Couldn't parse level file"), level_data)
+            }
         };
-        let interface = GameInterface::new();
-        let s = MainState {
-            timeline, renderer, interface, networking, conf, orders, player,
-            frame: 0, turn: 0, residual_update_dt: time::Duration::from_secs(0),
+        let player = match playback {
+            Some(ref p) => p.replay.player,
+            None => Player::P1,
+        };
+        //host: wait for the map's other players to join. join <ip[:port]>: join a host.
+        //spectate <ip[:port]>: watch a dedicated server's game. Otherwise, play alone.
+        let lobby = match mode {
+            Some("host") => {
+                let settings = GameSettings{level: level_path, map_hash: map_hash(&level_data), tick_time: conf.system.tick_time,
+                    command_delay: conf.system.command_delay, teams: conf.system.teams.clone(), timeline: conf.timeline.clone()};
+                let players = starting_players(&Simulation::new(map_loading::load_map(level.clone()).unwrap_or_else(|e| panic!("Bad level: {}", e))));
+                let port = conf.system.port_from.unwrap_or(40004);
+                Some(Lobby::Host(LobbyHost::new(settings, &players, port, Player::P1).unwrap_or_else(|e| panic!("Couldn't host on port {}: {}", port, e))))
+            }
+            Some("join") | Some("spectate") => {
+                let host = args.get(2).expect("join and spectate need the host's address");
+                let spectator = mode == Some("spectate");
+                Some(Lobby::Client(LobbyClient::new(host, conf.system.port_to.unwrap_or(40004), spectator).expect("Bad host address")))
+            }
+            _ => None,
+        };
+        let state = match lobby {
+            Some(_) => MenuState::Lobby,
+            None => MenuState::Playing,
+        };
+        let renderer = Renderer::new(ctx)?;
+        let command_delay = conf.system.command_delay;
+        let tick_time = conf.system.tick_time;
+        //placeholders until start_level
+        let timeline = Timeline::new(Simulation::new(map_loading::load_map(level.clone()).unwrap_or_default()), Vec::new(), TimelineConfig::default());
+        let recording = Replay::new(level.clone(), tick_time, player);
+        let mut s = MainState {
+            command_delay, wanted_delay: PlayerArr::new(None), orders: VecDeque::new(),
+            timeline, renderer, interface: GameInterface::new(false), networking: None, conf, player, recording, playback, lobby,
+            spectating: mode == Some("spectate"),
+            frame: 0, turn: 0, caught_up: 0, residual_update_dt: time::Duration::from_secs(0),
             last_instant: time::Instant::now(), last_turn: time::Instant::now(), state
         };
+        s.start_level(level).expect("Couldn't start the level");
+        if mode == Some("--load") {
+            let path = args.get(2).expect("--load needs a file");
+            s.load_game(path);
+        }
         Ok(s)
     }
+    //set up a new game on this level with the current settings, unless they won't work
+    fn start_level(&mut self, mut level: map_loading::LoadingMap) -> Result<(), String>{
+        if let (None, Some(teams)) = (self.playback.as_ref(), self.conf.system.teams.clone()){
+            map_loading::set_teams(&mut level, teams);//so the replay gets them too
+        }
+        if let (None, Some(config)) = (self.playback.as_ref(), self.conf.timeline.clone()){
+            map_loading::set_timeline(&mut level, config);
+        }
+        let conditions = map_loading::load_victory(&level);
+        let teams = map_loading::load_teams(&level)?;
+        let graph = map_loading::load_map(level.clone())?;
+        let mut sim = Simulation::with_teams(graph, teams);
+        sim.combat = map_loading::load_combat(&level)?;
+        self.timeline = Timeline::new(sim, conditions, map_loading::load_timeline(&level)?);
+        self.command_delay = self.conf.system.command_delay;
+        self.wanted_delay = PlayerArr::new(None);
+        self.orders = VecDeque::new();
+        for _ in 0..self.command_delay{
+            self.orders.push_front(Vec::new());
+        }
+        self.interface = GameInterface::new(map_loading::load_fog(&level));
+        self.interface.overlay = self.spectating;
+        self.interface.update_fog(&self.timeline[self.player], self.player);
+        self.recording = Replay::new(level, self.conf.system.tick_time, self.player);
+        Ok(())
+    }
+    fn update_lobby(&mut self, ctx: &mut Context){
+        let event = self.lobby.as_mut().unwrap().update();
+        match event {
+            LobbyEvent::Waiting => {},
+            LobbyEvent::LoadMap(player, settings) => {
+                //the host decides the settings
+                self.player = player;
+                self.conf.system.tick_time = settings.tick_time;
+                self.conf.system.command_delay = settings.command_delay;
+                self.conf.system.teams = settings.teams.clone();
+                self.conf.timeline = settings.timeline.clone();
+                self.conf.system.level = Some(settings.level.clone());
+                let loaded = match read_level(ctx, &settings.level) {
+                    Err(_) => Err(format!("You don't have the host's level {}", settings.level)),
+                    Ok(data) => match toml::from_slice::<map_loading::LoadingMap>(&data) {
+                        Err(e) => Err(format!("Couldn't parse the host's level {}: {}", settings.level, e)),
+                        Ok(level) => self.start_level(level).map(|()| map_hash(&data)),
+                    }
+                };
+                self.lobby.as_mut().unwrap().map_loaded(loaded);
+            }
+            LobbyEvent::Failed(reason) => {
+                println!("Couldn't join: {}", reason);
+                self.state = MenuState::LobbyFailed(reason);
+            }
+            LobbyEvent::Start => {
+                let result = self.lobby.take().unwrap().finish();
+                self.networking = Some(NetworkManager::from_lobby(result, &self.conf.system));
+                self.state = MenuState::WaitingForConnection;
+            }
+        }
+    }
 
     fn tick(&mut self) {
         let now = time::Instant::now();
@@ -152,6 +238,7 @@ impl MainState {
         self.residual_update_dt += time_since_last;
         self.last_instant = now;
         self.frame += 1;
+        self.caught_up = 0;
     }
     fn turn_tick(&mut self) {
         let now = time::Instant::now();;
@@ -163,9 +250,95 @@ impl MainState {
         self.residual_update_dt = time::Duration::from_secs(0);
 
     }
+    fn save_replay(&self){
+        if self.playback.is_some() {
+            return;//don't overwrite the recording with itself
+        }
+        if let Some(ref path) = self.conf.system.replay_file {
+            match self.recording.save(path) {
+                Ok(_) => println!("Replay saved to {}", path),
+                Err(e) => println!("Replay failed to save to {}: {}", path, e),
+            }
+        }
+    }
+    //F5 to quick-save, F9 to quick-load
+    fn save_key(&mut self, keycode: Keycode) -> bool{
+        let path = match (keycode, self.conf.system.save_file.clone()) {
+            (Keycode::F5, Some(path)) | (Keycode::F9, Some(path)) => path,
+            _ => return false,
+        };
+        if self.networking.is_some() || self.lobby.is_some() || self.watching() {
+            println!("Only offline games can be saved and loaded");
+        } else if keycode == Keycode::F5 {
+            match save::save_game(&path, &self.timeline, &self.orders, self.turn, self.player, &self.recording) {
+                Ok(_) => println!("Game saved to {} on turn {}", path, self.turn),
+                Err(e) => println!("Game failed to save to {}: {}", path, e),
+            }
+        } else {
+            self.load_game(&path);
+        }
+        true
+    }
+    fn load_game(&mut self, path: &str){
+        let saved = match save::load_game(path) {
+            Ok(saved) => saved,
+            Err(e) => {
+                println!("Game failed to load from {}: {}", path, e);
+                return;
+            }
+        };
+        self.conf.system.tick_time = saved.recording.tick_time;
+        self.timeline = saved.timeline;
+        self.orders = saved.orders;
+        self.command_delay = self.orders.len();
+        self.wanted_delay = PlayerArr::new(None);
+        self.turn = saved.turn;
+        self.player = saved.player;
+        //what was seen under the fog isn't saved, only what can be seen now
+        self.interface = GameInterface::new(map_loading::load_fog(&saved.recording.level));
+        self.interface.update_fog(&self.timeline[self.player], self.player);
+        self.recording = saved.recording;
+        self.state = MenuState::Playing;
+        self.reset_time();
+        println!("Game loaded from {} on turn {}", path, self.turn);
+    }
+    //replays and spectators only watch, they can't give orders
+    fn watching(&self) -> bool{
+        self.playback.is_some() || self.spectating
+    }
+    //0-4 to watch that player's timewave (0 is the present), tab to show every player at once.
+    //Replays also have space to pause and +/- to change speed.
+    fn view_key(&mut self, keycode: Keycode) -> bool{
+        if !self.watching() {
+            return false;
+        }
+        match keycode {
+            Keycode::Num0 => self.player = Player::PASSIVE,
+            Keycode::Num1 => self.player = Player::P1,
+            Keycode::Num2 => self.player = Player::P2,
+            Keycode::Num3 => self.player = Player::P3,
+            Keycode::Num4 => self.player = Player::P4,
+            Keycode::Tab => self.interface.overlay = !self.interface.overlay,
+            _ => {
+                if let Some(ref mut p) = self.playback {
+                    match keycode {
+                        Keycode::Space => p.paused = !p.paused,
+                        Keycode::Equals | Keycode::Plus | Keycode::KpPlus => p.faster(),
+                        Keycode::Minus | Keycode::KpMinus => p.slower(),
+                        _ => return false,
+                    }
+                    println!("Replay turn {}/{}: paused {}, speed x{}", p.next_turn, p.replay.turns.len(), p.paused, p.speed);
+                    return true;
+                }
+                return false;
+            }
+        }
+        println!("Watching {:?}", self.player);
+        true
+    }
     fn check_networking(&mut self) {
         if let Some(n) = self.networking.as_mut() {
-            n.receive_commands(&mut self.orders, self.turn, &self.conf.system);
+            n.receive_commands(&mut self.orders, self.turn);
         }
     }
     fn send_commands(&mut self){
@@ -173,8 +346,84 @@ impl MainState {
             n.send_commands(&mut self.orders, self.turn);
         }
     }
+    fn check_desync(&mut self){
+        let desynced = match self.networking.as_mut() {
+            Some(n) => {
+                n.send_hashes(&self.timeline);
+                n.desync.check()
+            }
+            None => None,
+        };
+        if let Some(d) = desynced {
+            println!("DESYNC: disagree with {:?} on the world at time {} (ours {:016x}, theirs {:016x})", d.peer, d.time, d.local_hash, d.remote_hash);
+            //they dump both sides once ours arrives, and we do the same with theirs
+            match (self.timeline.final_state(d.time), self.networking.as_mut()) {
+                (Some(world), Some(n)) => n.send_world(d.peer, d.time, &world),
+                _ => println!("Our world at time {} is no longer stored", d.time),
+            }
+        }
+        if let Some(n) = self.networking.as_mut() {
+            for (peer, time, world) in n.desync.worlds.drain(..) {
+                write_dump(self.player, self.timeline.final_state(time), peer, time, &world);
+            }
+        }
+    }
+    //every peer sees the same requests on the same turn, so they all change the delay together
+    fn apply_delay_requests(&mut self, orders_this_turn: &Vec<AchronalCommand>){
+        for order in orders_this_turn {
+            if let AchronalCommandTypes::CommandDelay(delay) = order.event {
+                if delay >= MIN_COMMAND_DELAY && delay <= MAX_COMMAND_DELAY {
+                    self.wanted_delay[order.player] = Some(delay);
+                }
+            }
+        }
+        let delay = Player::values().filter_map(|p| self.wanted_delay[p]).max().unwrap_or(self.conf.system.command_delay);
+        if delay != self.command_delay {
+            println!("Command delay changed from {} to {} on turn {}", self.command_delay, delay, self.turn);
+            self.command_delay = delay;
+        }
+    }
+    //ask for more delay as soon as we need it, but only come down one turn at a time
+    fn request_delay(&mut self){
+        if self.spectating || self.turn % DELAY_CHECK_TURNS != 0 {
+            return;
+        }
+        let wanted = match self.networking.as_ref().and_then(|n| n.wanted_delay(self.conf.system.tick_time)) {
+            Some(wanted) => wanted,
+            None => return,
+        };
+        let current = self.wanted_delay[self.player].unwrap_or(self.command_delay);
+        let request = if wanted > current {
+            wanted
+        } else if wanted < current {
+            current - 1
+        } else {
+            return;
+        };
+        let event = AchronalCommandTypes::CommandDelay(request);
+        self.orders.back_mut().unwrap().push(AchronalCommand{player: self.player, event});
+    }
+    fn tick_duration(&self) -> time::Duration {
+        let speed = self.playback.as_ref().map(|p| p.speed).unwrap_or(1);
+        time::Duration::from_millis(self.conf.system.tick_time as u64) / speed
+    }
     fn check_update(&mut self) -> bool {
-        let dt = time::Duration::from_millis(self.conf.system.tick_time as u64);
+        if let Some(n) = self.networking.as_mut() {
+            //far behind what the others have played, so don't wait for the clock
+            if n.turns_ready() > self.command_delay && self.caught_up < CATCH_UP_TURNS_PER_FRAME {
+                self.caught_up += 1;
+                n.advance();
+                return true;
+            }
+        }
+        let dt = self.tick_duration();
+        if let Some(ref p) = self.playback {
+            if p.paused || p.finished() {
+                self.residual_update_dt = time::Duration::from_secs(0);
+                self.last_turn = time::Instant::now();//stop dt-prediction
+                return false;
+            }
+        }
         if self.residual_update_dt > dt {
             self.residual_update_dt -= dt;
             if let Some(n) = self.networking.as_mut() {
@@ -195,7 +444,7 @@ impl MainState {
     }
     fn dt(&self) -> f32{
         let now = time::Instant::now();
-        let dt_expected = time::Duration::from_millis(self.conf.system.tick_time as u64);
+        let dt_expected = self.tick_duration();
         let dt_now = now-self.last_turn;
         let dt = ((dt_now.subsec_nanos() as f64)/(dt_expected.subsec_nanos() as f64)) as f32;
         dt
@@ -215,25 +464,55 @@ impl event::EventHandler for MainState {
                 self.check_networking();
                 while self.check_update() {
                     self.turn_tick();
-                    self.orders.push_back(Vec::new());
-                    let orders_this_turn = self.orders.pop_front().unwrap();
+                    let mut orders_this_turn = match self.playback {
+                        Some(ref mut p) => p.next_commands().unwrap_or_default(),
+                        None => {
+                            let local = self.orders.pop_front().unwrap();
+                            match self.networking {
+                                Some(ref mut n) => n.final_commands(self.turn-1, local),
+                                None => local,
+                            }
+                        }
+                    };
+                    if self.playback.is_none() {
+                        self.apply_delay_requests(&orders_this_turn);
+                        //refill to the delay. If it went down, the back slot stays open a little longer instead
+                        while self.orders.len() < self.command_delay {
+                            self.orders.push_back(Vec::new());
+                        }
+                        self.request_delay();
+                    }
                     if orders_this_turn.len() > 0 {
                         println!("process command on turn {}", self.turn)
                     }
+                    //peers merge each other's commands in different orders, so put them in a fixed one
+                    orders_this_turn.sort_by_key(|o| o.player);
+                    if self.playback.is_none() {
+                        self.recording.record(&orders_this_turn);
+                    }
                     self.timeline.evaluate_timestep(orders_this_turn);
                     self.send_commands();
+                    self.check_desync();
+                    if let Some(result) = self.timeline.result().cloned(){
+                        self.state = MenuState::Results(result);
+                        self.save_replay();
+                        break;
+                    }
                 }
+                self.interface.update_fog(&self.timeline[self.player], self.player);
             }
             MenuState::WaitingForConnection => {
                 let connected = {
                     let net = &mut self.networking.as_mut().unwrap();
-                    net.attempt_connect(self.player)
+                    net.attempt_connect()
                 };
                 if connected {
                     self.state = MenuState::Playing;
                     self.reset_time();
                 }
             }
+            MenuState::Lobby => self.update_lobby(ctx),
+            MenuState::Results(_) | MenuState::LobbyFailed(_) => {}
         }
 
         Ok(())
@@ -241,7 +520,24 @@ impl event::EventHandler for MainState {
 
     fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
         graphics::clear(ctx);
-        self.renderer.render(ctx, &self.conf.interface, self.player, &self.timeline, &self.interface, self.dt())?;
+        match self.state{
+            MenuState::Results(ref result) => {
+                let viewer = if self.watching() {Player::PASSIVE} else {self.player};
+                self.renderer.render_results(ctx, &self.conf.interface, viewer, &self.timeline, result)?;
+            }
+            MenuState::Lobby => {
+                let lines = self.lobby.as_ref().unwrap().status();
+                self.renderer.render_lines(ctx, &self.conf.interface, &lines, None)?;
+            }
+            MenuState::LobbyFailed(ref reason) => {
+                let lines = vec!["Couldn't join the game".to_string(), reason.clone()];
+                self.renderer.render_lines(ctx, &self.conf.interface, &lines, None)?;
+            }
+            _ => {
+                let net_status = self.networking.as_ref().map(|n| n.status(self.command_delay));
+                self.renderer.render(ctx, &self.conf.interface, self.player, &self.timeline, &self.interface, net_status.as_ref(), self.dt())?;
+            }
+        }
         graphics::present(ctx);
         Ok(())
     }
@@ -252,6 +548,9 @@ impl event::EventHandler for MainState {
                              button: MouseButton,
                              x: i32,
                              y: i32) {
+        if self.watching() {
+            return;
+        }
         self.interface.mouse_up(button, ipt(x, y), self.player, &self.timeline, &mut self.orders, &self.conf.interface);
     }
 
@@ -260,6 +559,9 @@ impl event::EventHandler for MainState {
                                button: MouseButton,
                                x: i32,
                                y: i32) {
+        if self.watching() {
+            return;
+        }
         self.interface.mouse_down(button, ipt(x, y), self.player, &self.timeline, &mut self.orders, &self.conf.interface);
     }
 
@@ -267,11 +569,11 @@ impl event::EventHandler for MainState {
         &mut self,
         _ctx: &mut Context,
         state: MouseState,
-        _x: i32,
-        _y: i32,
+        x: i32,
+        y: i32,
         xrel: i32,
         yrel: i32, ) {
-        self.interface.mouse_move(state, Vector2::new(xrel as f32, yrel as f32));
+        self.interface.mouse_move(state, ipt(x, y), Vector2::new(xrel as f32, yrel as f32), &self.timeline, &self.conf.interface);
     }
     fn mouse_wheel_event(&mut self, _ctx: &mut Context, _x: i32, _y: i32) {
         self.interface.mouse_wheel(_y);
@@ -279,7 +581,9 @@ impl event::EventHandler for MainState {
     //event::Mod to fix unresolved reference failure in IDE
     fn key_down_event(&mut self, _ctx: &mut Context, keycode: Keycode, _keymod: event::Mod, _repeat: bool) {
         if !_repeat{
-            self.interface.key_down(keycode);
+            if !self.save_key(keycode) && !self.view_key(keycode) {
+                self.interface.key_down(keycode);
+            }
         }
     }
     fn key_up_event(&mut self, _ctx: &mut Context, keycode: Keycode, _keymod: event::Mod, _repeat: bool) {
@@ -287,6 +591,17 @@ impl event::EventHandler for MainState {
             self.interface.key_up(keycode);
         }
     }
+    //the window is closing
+    fn quit_event(&mut self, _ctx: &mut Context) -> bool {
+        self.save_replay();
+        false
+    }
+}
+fn read_level(ctx: &mut Context, path: &str) -> GameResult<Vec<u8>>{
+    let mut level_file = ctx.filesystem.open(path)?;
+    let mut buffer = Vec::new();
+    level_file.read_to_end(&mut buffer)?;
+    Ok(buffer)
 }
 pub fn main() {
 
@@ -306,5 +621,8 @@ pub fn main() {
     }
 
     let state = &mut MainState::new(ctx).unwrap();
-    event::run(ctx, state).unwrap();
+    if let Err(e) = event::run(ctx, state) {
+        state.save_replay();//quitting normally already saved it
+        panic!("Game loop failed: {:?}", e);
+    }
 }
\ No newline at end of file
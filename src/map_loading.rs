@@ -1,14 +1,19 @@
 use simulation::*;
 use library::*;
+use victory::*;
+use timeline::TimelineConfig;
+use combat::CombatConfig;
 
 use std::collections::HashMap;
 
 use num::FromPrimitive;
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Globals{
-    map_size: Vec<i64>
+    map_size: Vec<i64>,
+    teams: Option<Vec<i64>>, //team of P1, P2... Players on the same team are allied
+    fog_of_war: Option<bool>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct MapPlanet{
     id: String,
     loc: Vec<i64>,
@@ -16,12 +21,36 @@ struct MapPlanet{
     count: Option<i64>,
     max_strength: Option<i64>,
     spawn_needed: Option<i64>,
+    production: Option<i64>, //spawn progress per tick, a unit is made every spawn_needed
+    defense: Option<i64>, //percent of normal fighting strength for whoever holds it
+    kind: Option<PlanetKind>,
     edges: Option<Vec<String>>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+//a lane with anything special about it. If the planets are already joined through edges, this replaces that lane.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct MapLane{
+    from: String,
+    to: String,
+    length: Option<i64>, //defaults to the distance between them
+    speed: Option<i64>, //percent of normal army speed
+    one_way: Option<bool>, //armies only go from -> to
+    capacity: Option<i64>, //most units on it at once
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct MapVictory{
+    elimination: Option<bool>,
+    domination_planets: Option<i64>,
+    domination_ticks: Option<i64>,
+    time_limit: Option<i64>,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LoadingMap{
     global: Globals,
-    planet: Vec<MapPlanet>
+    victory: Option<MapVictory>,
+    timeline: Option<TimelineConfig>,
+    combat: Option<CombatConfig>,
+    planet: Vec<MapPlanet>,
+    lane: Option<Vec<MapLane>>,
 }
 
 fn to_vec(p: &Ipt) -> Vec<i64>{
@@ -33,38 +62,161 @@ fn to_Ipt(p: Vec<i64>) -> Ipt{
     ipt(p[0] as i32, p[1] as i32)
 }
 
-//returns the graph
-pub fn load_map(map: LoadingMap) -> WorldGraph{
+//lane length per pixel between planets, so a lane takes as long as it looks
+const LANE_LENGTH_PER_PIXEL: f64 = 18.;
+fn lane_length(a: Ipt, b: Ipt) -> i32{
+    let (dx, dy) = ((a.x - b.x) as f64, (a.y - b.y) as f64);
+    (dx.hypot(dy)*LANE_LENGTH_PER_PIXEL).round() as i32
+}
+
+//limits on planet and lane numbers, so nothing they're multiplied by in the simulation can overflow
+const MAX_PRODUCTION: i64 = 1_000_000;
+const MAX_DEFENSE: i64 = 1000;
+const MAX_COUNT: i64 = 1_000_000;
+const MAX_STRENGTH: i64 = 1_000_000;
+const MAX_LANE_LENGTH: i64 = 1_000_000;
+const MAX_LANE_SPEED: i64 = 1000;
+//a number from the map, checked before it's narrowed. what is the planet or lane it's on.
+fn map_value(what: &str, name: &str, value: Option<i64>, default: i64, min: i64, max: i64) -> Result<i64, String>{
+    let value = value.unwrap_or(default);
+    if value < min || value > max{
+        return Err(format!("{}: {} must be from {} to {}, not {}", what, name, min, max, value));
+    }
+    Ok(value)
+}
+
+//returns the graph, or what's wrong with the map
+pub fn load_map(map: LoadingMap) -> Result<WorldGraph, String>{
 
     let mut g = Graph::default();
     let mut data: HashMap<String, NodeInd> = HashMap::new();
     for p in map.planet{
         let loc = to_Ipt(p.loc);
         let owner = Player::from_i64(p.owner.unwrap_or(Player::PASSIVE as i64)).unwrap();
-        let max_strength = p.max_strength.unwrap_or(64) as u32;
+        let what = format!("Planet {}", p.id);
+        let max_strength = map_value(&what, "max_strength", p.max_strength, 64, 1, MAX_STRENGTH)? as u32;
 
         let mut count = PlayerArr::new(0);
-        count[owner] = p.count.unwrap_or(10) as u32;
+        count[owner] = map_value(&what, "count", p.count, 10, 0, MAX_COUNT)? as u32;
         let node = Planet{
             loc,
             count,
             fight_progess: PlayerArr::new(0),
+            battle_ticks: 0,
             send_all: PlayerArr::new(None),
             owner,
             owner_strength: max_strength,
             max_strength,
             spawn_progress: 0,
+            production: map_value(&what, "production", p.production, PRODUCTION as i64, 1, MAX_PRODUCTION)? as u32,
+            spawn_needed: map_value(&what, "spawn_needed", p.spawn_needed, SPAWN_NEEDED as i64, 1, u32::max_value() as i64)? as u32,
+            defense: map_value(&what, "defense", p.defense, 100, 1, MAX_DEFENSE)? as u32,
+            kind: p.kind.unwrap_or(PlanetKind::Normal),
         };
         let node_ind = g.add_node(node);
         data.insert(p.id, node_ind);
         if let Some(edges) = p.edges {
             for e in edges{
-                let other = data.get(&e).unwrap();
-                g.add_edge(node_ind, *other, HyperLane::new());
+                let other = data.get(&e).ok_or(format!("{}: edge to {}, which isn't an earlier planet", what, e))?;
+                let length = lane_length(loc, g[*other].loc);
+                g.add_edge(node_ind, *other, HyperLane::new(length));
             }
         }
     }
-    g
+    for l in map.lane.unwrap_or_default(){
+        let what = format!("Lane {} to {}", l.from, l.to);
+        let from = *data.get(&l.from).ok_or(format!("{}: there's no planet {}", what, l.from))?;
+        let to = *data.get(&l.to).ok_or(format!("{}: there's no planet {}", what, l.to))?;
+        let length = map_value(&what, "length", l.length, lane_length(g[from].loc, g[to].loc) as i64, 1, MAX_LANE_LENGTH)?;
+        let mut lane = HyperLane::new(length as i32);
+        lane.speed = map_value(&what, "speed", l.speed, 100, 1, MAX_LANE_SPEED)? as i32;
+        if let Some(capacity) = l.capacity{
+            lane.capacity = Some(map_value(&what, "capacity", Some(capacity), 0, 1, u32::max_value() as i64)? as u32);
+        }
+        let edge_ind = match g.find_edge(from, to) {
+            Some(edge_ind) => edge_ind,
+            None => g.add_edge(from, to, HyperLane::new(0)),
+        };
+        //the edge may run either way between them
+        let forward = g.edge_endpoints(edge_ind).unwrap().0 == from;
+        if l.one_way.unwrap_or(false) {
+            lane.one_way = Some(if forward {DIR::FORWARD} else {DIR::BACKWARD});
+        }
+        g[edge_ind] = lane;
+    }
+    Ok(g)
+}
+//planet ids from the map file to their index in the graph returned by load_map
+pub fn planet_ids(map: &LoadingMap) -> HashMap<String, NodeInd>{
+    let mut data = HashMap::new();
+    for (i, p) in map.planet.iter().enumerate(){
+        data.insert(p.id.clone(), NodeInd::new(i));
+    }
+    data
+}
+//returns the victory conditions, defaulting to elimination only
+pub fn load_victory(map: &LoadingMap) -> Vec<VictoryCondition>{
+    let mut conditions = Vec::new();
+    if let Some(ref v) = map.victory{
+        if v.elimination.unwrap_or(true){
+            conditions.push(VictoryCondition::Elimination);
+        }
+        if let Some(planets) = v.domination_planets{
+            let ticks = v.domination_ticks.unwrap_or(0) as u32;
+            conditions.push(VictoryCondition::Domination{planets: planets as u32, ticks});
+        }
+        if let Some(limit) = v.time_limit{
+            conditions.push(VictoryCondition::TimeLimit(limit as ChronalTime));
+        }
+    } else {
+        conditions.push(VictoryCondition::Elimination);
+    }
+    conditions
+}
+//returns the team of each player, defaulting to everyone on their own team
+pub fn load_teams(map: &LoadingMap) -> Result<PlayerArr<Team>, String>{
+    match map.global.teams{
+        Some(ref list) => teams_from_list(list),
+        None => Ok(default_teams()),
+    }
+}
+//teams given as P1's, P2's... team. They can come from the host over the network, so a bad list is an error, not a crash.
+pub fn teams_from_list(list: &Vec<i64>) -> Result<PlayerArr<Team>, String>{
+    let mut teams = default_teams();
+    for (i, &team) in list.iter().enumerate(){
+        let player = Player::from_i64(i as i64 + 1).ok_or(format!("{} teams listed, but there are only {} players", list.len(), i))?;
+        if team < 1 || team > 255{
+            return Err(format!("{:?} is on team {}, teams are numbered 1 to 255", player, team));
+        }
+        teams[player] = team as Team;
+    }
+    Ok(teams)
+}
+//fog of war is on unless the map turns it off
+pub fn load_fog(map: &LoadingMap) -> bool{
+    map.global.fog_of_war.unwrap_or(true)
+}
+//the map's timeline rules, defaulting to the usual ones
+pub fn load_timeline(map: &LoadingMap) -> Result<TimelineConfig, String>{
+    let config = map.timeline.clone().unwrap_or_default();
+    match config.problem(){
+        Some(problem) => Err(format!("Bad [timeline] section: {}", problem)),
+        None => Ok(config),
+    }
+}
+//the map's combat rules, defaulting to the usual ones
+pub fn load_combat(map: &LoadingMap) -> Result<CombatConfig, String>{
+    let config = map.combat.clone().unwrap_or_default();
+    map_value("Bad [combat] section", "defender_advantage", Some(config.defender_advantage as i64), 100, 1, MAX_DEFENSE)?;
+    Ok(config)
+}
+//replace the map's timeline rules, for when they're picked outside the map file
+pub fn set_timeline(map: &mut LoadingMap, config: TimelineConfig){
+    map.timeline = Some(config);
+}
+//replace the map's teams, for when they're picked outside the map file
+pub fn set_teams(map: &mut LoadingMap, teams: Vec<i64>){
+    map.global.teams = Some(teams);
 }
 /*pub fn save_map(g: &Graph<Planet, Edge, Undirected>, size: &Ipt) -> LoadingMap{
     let glob = Globals{map_size: to_vec(size)};
@@ -1,124 +1,459 @@
 //use std::io::prelude::*;
 use std::io::ErrorKind;
 use std::net::*;
-use std::str::FromStr;
-use std::cmp::*;
 use simulation::*;
-use std::collections::VecDeque;
+use timeline::*;
+use desync::*;
+use transport::*;
+use lobby::*;
+use std::collections::{VecDeque, BTreeMap};
+use std::cmp::{min, max};
+use std::time::{Duration, Instant};
 use bincode::*;
 
+//how long a peer can go quiet before it counts as dropped
+pub const DROP_MS: u64 = 3000;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SystemConfig{
     pub tick_time: u32,
     pub command_delay: usize,
-    pub port_from: Option<u16>,
-    pub port_to: Option<u16>,
+    pub port_from: Option<u16>, //port to host on
+    pub port_to: Option<u16>, //port to join on, if the address doesn't have one
+    pub replay_file: Option<String>, //where the replay is written on exit
+    pub save_file: Option<String>, //where quick-save writes to and quick-load reads from
+    pub level: Option<String>, //map to play, in the resources folder
+    pub teams: Option<Vec<i64>>, //overrides the map's teams, must match between peers
+}
+
+pub struct Peer{
+    pub addr: SocketAddrV4,
+    pub player: Option<Player>, //known once they've connected
+    received: VecDeque<bool>,
+    channel: Channel,
+    relay: bool, //a server that decides everyone's commands
+    relayed: BTreeMap<u64, Vec<AchronalCommand>>, //final commands from the relay, by turn
+    early: BTreeMap<u64, Vec<AchronalCommand>>, //their commands for turns past the end of our buffer, merged in when the turn happens
+    last_heard: Instant,
+    log: BTreeMap<u64, Vec<AchronalCommand>>, //their commands for recent turns, to pass on if they drop
+    forwarded: bool, //we've passed on what we have of their commands since they went quiet
+    settle: BTreeMap<Player, u32>, //parts still to come of the others passing on their commands
+    joined: bool, //false while they're rejoining and still loading the map
+    resend: bool, //they've just rejoined, and need our commands for the turns that haven't happened yet
+}
+impl Peer{
+    //then someone else can take their place
+    fn dropped(&self) -> bool{
+        Instant::now() - self.last_heard > Duration::from_millis(DROP_MS)
+    }
+    fn rejoin(&mut self, addr: SocketAddrV4){
+        self.addr = addr;
+        self.channel = Channel::new();
+        self.last_heard = Instant::now();
+    }
 }
 
 pub struct NetworkManager{
     sock: UdpSocket,
-    target: SocketAddrV4,
-    received: VecDeque<bool>, //TODO: handle >2 players
+    player: Player,
+    pub peers: Vec<Peer>,
+    pub desync: DesyncChecker,
+    sent_through: u64, //every turn before this has had our commands sent
+    pub stalls: u32, //turns we had to wait on a peer
+    settings: GameSettings, //for anyone rejoining
+    history: Vec<Vec<AchronalCommand>>, //every final turn so far, for anyone rejoining
+}
+
+//shown in the UI
+#[derive(Clone, Debug)]
+pub struct NetStatus{
+    pub command_delay: usize,
+    pub stalls: u32,
+    pub rtt_ms: Option<f32>, //worst of the peers
+    pub jitter_ms: f32,
+    pub dropped: Vec<Player>, //peers we're waiting on that have gone quiet, PASSIVE for the server
 }
 
-const PACKET_CONNECT: u8 = 0;
-const PACKET_ORDER: u8 = 1;
+//every packet starts with the packet type, then the player that sent it
+pub const PACKET_CONNECT: u8 = 0;
+pub const PACKET_DATA: u8 = 1; //a fragment of a reliable message
+pub const PACKET_HASH: u8 = 2;
+pub const PACKET_ACK: u8 = 3;
+pub const PACKET_LOBBY: u8 = 4; //handled by the lobby, anything left over is ignored
+pub const PACKET_SIZE: usize = 2+FRAGMENT_HEADER+MAX_PAYLOAD;
+
+//every reliable message starts with its type
+pub const MESSAGE_TURNS: u8 = 0; //TurnCommands to a peer or the relay, RelayTurns from the relay
+pub const MESSAGE_WORLD: u8 = 1; //the world where we disagree with someone, so their dump has both sides
+pub const MESSAGE_HISTORY: u8 = 2; //RelayTurns from a peer, for someone rejoining a peer to peer game
+pub const MESSAGE_FORWARD: u8 = 3; //ForwardedTurns, a dropped peer's commands
+pub const MAX_BODY: usize = MAX_MESSAGE-1;
+//room for a turn's commands to be forwarded
+const MAX_TURN_BODY: usize = MAX_BODY-16;
+//turns per history message for someone rejoining, fewer if they don't fit
+const HISTORY_TURNS: usize = 512;
+
+//the commands one player gave on one turn, sent once the turn is sealed
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TurnCommands{
+    pub turn: u64,
+    pub commands: Vec<AchronalCommand>,
+}
+//from a relay: everyone's final commands for some turns in a row.
+//Usually just the latest, but someone rejoining gets the whole game so far.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RelayTurns{
+    pub first_turn: u64,
+    pub turns: Vec<Vec<AchronalCommand>>,
+}
+//A dropped peer's commands, passed on between the peers still playing: some of us may have got turns the others didn't,
+//and everyone has to agree on them before someone takes their place. Split into parts if they don't fit in one message.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ForwardedTurns{
+    pub player: Player,
+    pub parts: u32,
+    pub turns: Vec<TurnCommands>,
+}
 
 impl NetworkManager{
-    pub fn new(ip: &str, conf: &SystemConfig) -> NetworkManager{
-        let (port_to, port_from) = (conf.port_from.unwrap(), conf.port_to.unwrap());
-        let addr = format!("{}:{}","0.0.0.0",port_from);
-        let target = SocketAddrV4::from_str(&format!("{}:{}",ip,port_to)).expect("Error parsing Socket Addr V4");
-        let sock = UdpSocket::bind(&addr).expect(&format!("Error binding socket to {}",&addr));
-        sock.set_nonblocking(true).expect("socket nonblocking failed");
-        let mut received = VecDeque::new();
-        for _ in 0..conf.command_delay{
-            received.push_front(true);
-        }
-        NetworkManager{sock, target, received}
-    }
-    pub fn attempt_connect(&mut self, _player: Player) -> bool{
-        //send connection request
-        let mut buf = [0; 512];
-        let request= [0;1];
-        self.sock.send_to(&request, self.target).expect("Sending failed");
-        //and see if someone is connecting to us
-        match self.sock.recv_from(&mut buf) {
-            Ok(n) => {
-                let (number_of_bytes, src_addr) = n;
-                if buf[0] == PACKET_CONNECT && number_of_bytes == 1{
-                    match src_addr{
-                        SocketAddr::V4(src_addr_v4) =>{
-                            if src_addr_v4 != self.target {
-                                println!("Detected incoming-- gave up on {}, connecting to {}", self.target, &src_addr_v4);
-                                self.target = src_addr_v4;
-                                //and send them a request. Note that if this is dropped, things are bad.
-                                //But this should never happen anyway, so w/e
-                                let request = [0;1];
-                                self.sock.send_to(&request, self.target).expect("Sending failed");
+    //takes over the lobby's socket. Peers we haven't heard from yet still need to connect.
+    pub fn from_lobby(lobby: LobbyResult, conf: &SystemConfig) -> NetworkManager{
+        //the back slot is still open for orders until the first turn seals it, so it hasn't been received yet
+        assert!(conf.command_delay >= MIN_COMMAND_DELAY, "command_delay must be at least {} for a network game", MIN_COMMAND_DELAY);
+        let mut peers = Vec::new();
+        for peer in lobby.peers{
+            let mut received = VecDeque::new();
+            for i in 0..conf.command_delay{
+                received.push_back(i+1 < conf.command_delay);
+            }
+            let player = if peer.connected {Some(peer.player)} else {None};
+            peers.push(Peer{addr: peer.addr, player, received, channel: Channel::new(), relay: peer.relay, relayed: BTreeMap::new(), early: BTreeMap::new(), last_heard: Instant::now(),
+                log: BTreeMap::new(), forwarded: false, settle: BTreeMap::new(), joined: true, resend: false});
+        }
+        let sent_through = (conf.command_delay-1) as u64;
+        NetworkManager{sock: lobby.sock, player: lobby.player, peers, desync: DesyncChecker::new(), sent_through, stalls: 0, settings: lobby.settings, history: Vec::new()}
+    }
+    fn send_to(&self, addr: SocketAddrV4, kind: u8, data: &[u8]){
+        let mut buf = Vec::with_capacity(2+data.len());
+        buf.push(kind);
+        buf.push(self.player as u8);
+        buf.extend_from_slice(data);
+        if let Err(e) = self.sock.send_to(&buf, addr){
+            println!("Sending to {} failed: {}", addr, e);
+        }
+    }
+    fn send_to_all(&self, kind: u8, data: &[u8]){
+        for peer in &self.peers{
+            self.send_to(peer.addr, kind, data);
+        }
+    }
+    //send a message to every peer reliably
+    fn send_reliable(&mut self, kind: u8, body: &[u8]){
+        for i in 0..self.peers.len(){
+            self.send_reliable_to(i, kind, body);
+        }
+    }
+    fn send_reliable_to(&mut self, peer: usize, kind: u8, body: &[u8]){
+        let fragments = self.peers[peer].channel.send(&reliable_message(kind, body));
+        for fragment in fragments{
+            self.send_to(self.peers[peer].addr, PACKET_DATA, &fragment);
+        }
+    }
+    //resend anything that hasn't been acked, and ack what we've received
+    fn flush(&mut self){
+        for i in 0..self.peers.len(){
+            let addr = self.peers[i].addr;
+            let mut packets: Vec<(u8, Vec<u8>)> = Vec::new();
+            for fragment in self.peers[i].channel.resend(){
+                packets.push((PACKET_DATA, fragment));
+            }
+            for ack in self.peers[i].channel.take_acks(){
+                packets.push((PACKET_ACK, ack));
+            }
+            for (kind, data) in packets{
+                self.send_to(addr, kind, &data);
+            }
+        }
+    }
+    fn find_peer(&mut self, src_addr: &SocketAddr, player: Player) -> Option<usize>{
+        match *src_addr{
+            SocketAddr::V4(src_addr_v4) => {
+                if let Some(i) = self.peers.iter().position(|p| p.player == Some(player)){
+                    if self.peers[i].addr != src_addr_v4{
+                        if self.peers[i].dropped() && !self.peers[i].relay{
+                            //someone else let them back in, they need our commands from here on
+                            println!("{:?} is rejoining from {}", player, &src_addr_v4);
+                            self.peers[i].rejoin(src_addr_v4);
+                            self.peers[i].resend = true;
+                            self.send_to(src_addr_v4, PACKET_CONNECT, &[]);
+                        } else {
+                            println!("Packet from {:?} at {}, but connected to {}", player, &src_addr_v4, self.peers[i].addr);
+                        }
+                    }
+                    return Some(i);
+                }
+                if let Some(i) = self.peers.iter().position(|p| p.player.is_none() && p.addr == src_addr_v4){
+                    return Some(i);
+                }
+                //an unknown address can take over the last unconnected peer
+                let unconnected: Vec<usize> = (0..self.peers.len()).filter(|&i| self.peers[i].player.is_none()).collect();
+                if unconnected.len() == 1{
+                    let i = unconnected[0];
+                    println!("Detected incoming-- gave up on {}, connecting to {}", self.peers[i].addr, &src_addr_v4);
+                    self.peers[i].addr = src_addr_v4;
+                    return Some(i);
+                }
+                println!("Packet received from unknown peer {}, discarding", &src_addr_v4);
+                None
+            },
+            SocketAddr::V6(_) => {
+                println!("Packet received from ipv6, discarding");
+                None
+            },
+        }
+    }
+    //a spectator watches the relay's turns, without a player of its own
+    pub fn spectating(&self) -> bool{
+        self.player == Player::PASSIVE
+    }
+    pub fn connected(&self) -> bool{
+        self.peers.iter().all(|p| p.player.is_some())
+    }
+    pub fn attempt_connect(&mut self) -> bool{
+        //send connection requests
+        let mut buf = [0; PACKET_SIZE];
+        self.send_to_all(PACKET_CONNECT, &[]);
+        //and see if anyone is connecting to us. An order means they already saw our request.
+        loop {
+            match self.sock.recv_from(&mut buf) {
+                Ok(n) => {
+                    let (number_of_bytes, src_addr) = n;
+                    //anything else from them means they already saw our request. Unacked data will be resent.
+                    if number_of_bytes >= 2 && buf[0] <= PACKET_ACK{
+                        match Player::checked_from_usize(buf[1] as usize){
+                            Some(player) => {
+                                if let Some(i) = self.find_peer(&src_addr, player){
+                                    if self.peers[i].player.is_none(){
+                                        println!("Connected to {:?} at {}", player, self.peers[i].addr);
+                                        self.peers[i].player = Some(player);
+                                        self.peers[i].last_heard = Instant::now();
+                                        //make sure they hear from us, even if they started after our last request
+                                        let addr = self.peers[i].addr;
+                                        self.send_to(addr, PACKET_CONNECT, &[]);
+                                    }
+                                }
                             }
-                        },
-                        SocketAddr::V6(_) => println!("Packet received from ipv6, discarding"),
+                            None => println!("Bad player {} in packet from {}", buf[1], &src_addr),
+                        }
+                    } else if buf[0] != PACKET_LOBBY {
+                        println!("Unknown packet received from {} (first byte {})", &src_addr, buf[0]);
                     }
-                    println!("Connected!");
-                    return true;
-                } else {
-                    println!("Unknown packet received from {} (first byte {})", &src_addr, buf[0]);
                 }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(e) => println!("encountered IO error: {}", e),
+            }
+        }
+        if self.connected(){
+            println!("Connected!");
+            true
+        } else {
+            false
+        }
+    }
+    fn process_message(&mut self, peer: usize, orders: &mut CommandBuffer, message: &[u8], turn_t: u64){
+        let peer_player = self.peers[peer].player.unwrap();
+        match message.split_first() {
+            Some((&MESSAGE_TURNS, body)) => self.process_commands(peer, orders, body, turn_t),
+            Some((&MESSAGE_HISTORY, body)) => self.process_relayed(peer, body, turn_t),
+            Some((&MESSAGE_FORWARD, body)) => self.process_forwarded(peer, orders, body, turn_t),
+            Some((&MESSAGE_WORLD, body)) => match config().limit(MAX_MESSAGE as u64).deserialize::<(ChronalTime, Simulation)>(body) {
+                Ok((time, world)) => self.desync.worlds.push((peer_player, time, world)),
+                Err(e) => println!("Bad world from {:?}: {}", peer_player, e),
+            },
+            _ => println!("Unknown message from {:?}", peer_player),
+        }
+    }
+    //put a peer's commands for a sealed turn into our buffer. They are the authority on their own commands.
+    fn process_commands(&mut self, peer: usize, orders: &mut CommandBuffer, message: &[u8], turn_t: u64){
+        if self.peers[peer].relay{
+            self.process_relayed(peer, message, turn_t);
+            return;
+        }
+        let peer_player = self.peers[peer].player.unwrap();
+        let rec: TurnCommands = match config().limit(MAX_MESSAGE as u64).deserialize(message){
+            Ok(rec) => rec,
+            Err(e) => {
+                println!("Bad commands from {:?}: {}", peer_player, e);
+                return;
+            }
+        };
+        if rec.turn < turn_t{
+            println!("{:?} sent commands for turn {}, which has already happened", peer_player, rec.turn);
+            return;
+        }
+        //a peer can be at most a command delay ahead of us, with its own delay on top of that.
+        //While we catch up after rejoining, that's ahead of the history we were sent.
+        let caught_up = max(turn_t, self.history_end());
+        if rec.turn >= caught_up + (orders.len() + MAX_COMMAND_DELAY) as u64{
+            println!("{:?} sent commands for turn {}, too far ahead of turn {}", peer_player, rec.turn, turn_t);
+            return;
+        }
+        //they're back
+        self.peers[peer].forwarded = false;
+        self.peers[peer].settle.clear();
+        let commands = rec.commands.into_iter().filter(|o| o.player == peer_player).collect();
+        self.take_commands(peer, orders, rec.turn, commands, turn_t);
+    }
+    fn take_commands(&mut self, peer: usize, orders: &mut CommandBuffer, turn: u64, commands: Vec<AchronalCommand>, turn_t: u64){
+        let peer_player = self.peers[peer].player.unwrap();
+        let mine = (turn - turn_t) as usize;
+        let peer = &mut self.peers[peer];
+        peer.log.insert(turn, commands.clone());
+        while peer.received.len() <= mine{
+            peer.received.push_back(false);
+        }
+        peer.received[mine] = true;
+        if mine < orders.len(){
+            orders[mine].retain(|o| o.player != peer_player);
+            orders[mine].extend(commands);
+        } else {
+            //our own orders for it haven't started yet, so the buffer isn't stretched to fit
+            peer.early.insert(turn, commands);
+        }
+    }
+    //a dropped peer's commands from someone else. We only take turns we're missing, any we have are the same.
+    fn process_forwarded(&mut self, from: usize, orders: &mut CommandBuffer, message: &[u8], turn_t: u64){
+        let from_player = self.peers[from].player.unwrap();
+        let ForwardedTurns{player, parts, turns} = match config().limit(MAX_MESSAGE as u64).deserialize(message){
+            Ok(rec) => rec,
+            Err(e) => {
+                println!("Bad forwarded commands from {:?}: {}", from_player, e);
+                return;
+            }
+        };
+        let peer = match self.peers.iter().position(|p| p.player == Some(player) && !p.relay){
+            Some(i) if i != from => i,
+            _ => {
+                println!("{:?} forwarded commands for {:?}, who isn't another peer", from_player, player);
+                return;
             }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                // wait until network socket is ready, typically implemented
-                // via platform-specific APIs such as epoll or IOCP
-                //wait_for_fd();
-                //println!("no socket data");
+        };
+        for turn in turns{
+            if turn.turn < turn_t || turn.turn >= turn_t + (orders.len() + MAX_COMMAND_DELAY) as u64{
+                continue;
+            }
+            if self.peers[peer].received.get((turn.turn - turn_t) as usize) != Some(&true){
+                let commands = turn.commands.into_iter().filter(|o| o.player == player).collect();
+                self.take_commands(peer, orders, turn.turn, commands, turn_t);
             }
-            Err(e) => println!("encountered IO error: {}", e),
         }
-        return false;
+        let left = self.peers[peer].settle.entry(from_player).or_insert(parts);
+        *left = left.saturating_sub(1);
     }
-    fn process_commands(&mut self, orders: &mut CommandBuffer, buf: &[u8], turn_t: u64, conf: &SystemConfig){
-        let rec_turn_t: u64 =  deserialize_from(&buf[0..8]).unwrap();
-        let rec_turn = rec_turn_t as usize;
-        let turn = turn_t as usize;
-        let mut rec_orders: CommandBuffer = deserialize_from(&buf[8..]).unwrap();
-        let from = max(turn, rec_turn);
-        let to = rec_turn+conf.command_delay;
-        println!("myturn:{},rec_turn:{},to:{},rec_orders:{},orders:{},recieved:{:?}",turn, rec_turn, to, rec_orders.len(), orders.len(), self.received);
-        for i in from..to{
-            let mine = i-turn;
-            let rec = i-rec_turn;
-            if orders.len() <= mine{
-                orders.push_back(Vec::new());
-                self.received.push_back(true);
-            } else {
-                self.received[mine] = true;
+    //a relay's final commands are kept aside until the turn happens, our own slot may still be open
+    fn process_relayed(&mut self, peer: usize, message: &[u8], turn_t: u64){
+        let rec: RelayTurns = match config().limit(MAX_MESSAGE as u64).deserialize(message){
+            Ok(rec) => rec,
+            Err(e) => {
+                println!("Bad turns from the server: {}", e);
+                return;
+            }
+        };
+        for (i, commands) in rec.turns.into_iter().enumerate(){
+            let turn = rec.first_turn + i as u64;
+            if turn < turn_t{
+                continue;
             }
-            for o in rec_orders[rec].drain(0..){
-                if !orders[mine].contains(&o){
-                    orders[mine].push(o);
+            //the whole turn, so nothing else is needed for it
+            let mine = (turn - turn_t) as usize;
+            for peer in &mut self.peers{
+                while peer.received.len() <= mine{
+                    peer.received.push_back(false);
+                }
+                peer.received[mine] = true;
+            }
+            self.peers[peer].relayed.insert(turn, commands);
+        }
+    }
+    //the turn after the last one in the history we were sent, if any
+    fn history_end(&self) -> u64{
+        self.peers.iter().filter_map(|p| p.relayed.keys().next_back()).max().map_or(0, |&turn| turn+1)
+    }
+    //what actually happens on a turn. With a relay that's whatever it decided, otherwise it's our buffer,
+    //or the history we were sent if we rejoined.
+    pub fn final_commands(&mut self, turn: u64, mut local: Vec<AchronalCommand>) -> Vec<AchronalCommand>{
+        for peer in &mut self.peers{
+            if let (Some(commands), Some(player)) = (peer.early.remove(&turn), peer.player){
+                local.retain(|o| o.player != player);
+                local.extend(commands);
+            }
+        }
+        for peer in &mut self.peers{
+            if peer.relay{
+                let relayed = peer.relayed.remove(&turn).unwrap_or_default();
+                let player = self.player;
+                if local.iter().any(|o| o.player == player) && !relayed.iter().any(|o| o.player == player){
+                    println!("Our commands for turn {} were too late for the server", turn);
                 }
+                local = relayed;
+            } else if let Some(relayed) = peer.relayed.remove(&turn){
+                local = relayed;
             }
         }
+        if turn == self.history.len() as u64{
+            self.history.push(local.clone());
+        }
+        local
     }
-    pub fn receive_commands(&mut self, orders: &mut CommandBuffer, turn: u64, conf: &SystemConfig){
-        let mut buf = [0; 512];
-        //first check if someone is connecting to us
+    pub fn receive_commands(&mut self, orders: &mut CommandBuffer, turn: u64){
+        let mut buf = [0; PACKET_SIZE];
         loop {
             match self.sock.recv_from(&mut buf) {
                 Ok(n) => {
                     let (number_of_bytes, src_addr) = n;
-                    if buf[0] == PACKET_ORDER {
-                        match src_addr {
-                            SocketAddr::V4(src_addr_v4) => {
-                                if src_addr_v4 != self.target {
-                                    println!("Packet from {}, but connected to {}", self.target, &src_addr_v4);
-                                }
-                                let reader = &buf[1..number_of_bytes];
-                                self.process_commands(orders, reader, turn, conf);
-                            },
-                            SocketAddr::V6(_) => println!("Packet received from ipv6, discarding"),
+                    if number_of_bytes < 2{
+                        println!("Runt packet received from {}", &src_addr);
+                        continue;
+                    }
+                    if buf[0] == PACKET_LOBBY {
+                        if let SocketAddr::V4(addr) = src_addr{
+                            self.receive_lobby(addr, &buf[2..number_of_bytes]);
+                        }
+                        continue;
+                    }
+                    let peer = match Player::checked_from_usize(buf[1] as usize){
+                        Some(player) => self.find_peer(&src_addr, player),
+                        None => None,
+                    };
+                    let peer = match peer{
+                        Some(i) if self.peers[i].player.is_some() => i,
+                        _ => {
+                            if buf[0] != PACKET_CONNECT{
+                                println!("Packet from {}, which isn't a connected peer", &src_addr);
+                            }
+                            continue;
+                        }
+                    };
+                    self.peers[peer].last_heard = Instant::now();
+                    let data = &buf[2..number_of_bytes];
+                    if buf[0] == PACKET_DATA {
+                        match self.peers[peer].channel.receive_fragment(data) {
+                            Ok(Some(message)) => self.process_message(peer, orders, &message, turn),
+                            Ok(None) => {},
+                            Err(e) => println!("Bad fragment from {}: {}", &src_addr, e),
+                        }
+                    } else if buf[0] == PACKET_ACK {
+                        if let Err(e) = self.peers[peer].channel.receive_acks(data) {
+                            println!("Bad ack from {}: {}", &src_addr, e);
+                        }
+                    } else if buf[0] == PACKET_HASH {
+                        match config().limit(PACKET_SIZE as u64).deserialize::<Vec<(ChronalTime, u64)>>(data) {
+                            Ok(hashes) => self.desync.record_remote(self.peers[peer].player.unwrap(), &hashes),
+                            Err(e) => println!("Bad hash packet from {}: {}", &src_addr, e),
                         }
-                    } else {
+                    } else if buf[0] != PACKET_CONNECT {
                         println!("Unknown packet received from {} (first byte {})", &src_addr, buf[0]);
                     }
                 }
@@ -132,27 +467,274 @@ impl NetworkManager{
                 Err(e) => println!("encountered IO error: {}", e),
             }
         }
+        for i in 0..self.peers.len(){
+            if self.peers[i].resend{
+                self.peers[i].resend = false;
+                self.resend_commands(i, orders, turn);
+            }
+        }
+        //the others can be at most a command delay behind us
+        let keep_from = turn.saturating_sub(MAX_COMMAND_DELAY as u64);
+        for peer in &mut self.peers{
+            peer.log = peer.log.split_off(&keep_from);
+        }
+        self.forward_dropped();
+        self.flush();
+    }
+    fn reject(&self, addr: SocketAddrV4, reason: String){
+        println!("Rejected {}: {}", addr, reason);
+        send_lobby(&self.sock, self.player, addr, &LobbyMessage::Reject{reason});
+    }
+    //someone joining once the game is going, to take the place of a peer that dropped. They get the settings and
+    //every final turn so far to re-simulate, like from a dedicated server. Anything else is the host resending the start.
+    fn receive_lobby(&mut self, addr: SocketAddrV4, data: &[u8]){
+        let message = match parse_lobby(data) {
+            Ok(message) => message,
+            Err(e) => {
+                println!("Bad lobby packet from {}: {}", addr, e);
+                return;
+            }
+        };
+        let peer = self.peers.iter().position(|p| p.addr == addr && !p.relay);
+        match message {
+            LobbyMessage::Hello{protocol, build, spectator} => {
+                if self.peers.iter().any(|p| p.relay) {
+                    return self.reject(addr, "Join the game's server instead".to_string());
+                }
+                if let Some(reason) = hello_problem(protocol, build) {
+                    return self.reject(addr, reason);
+                }
+                if spectator {
+                    return self.reject(addr, "Only a dedicated server can take spectators".to_string());
+                }
+                let i = match peer {
+                    Some(i) if !self.peers[i].joined => i,//they missed the welcome
+                    _ => match self.peers.iter().position(|p| p.dropped()) {
+                        None => return self.reject(addr, "The game has already started, and nobody has dropped".to_string()),
+                        Some(i) if !self.ready_for_rejoin(i) => {
+                            //they keep saying hello until we answer
+                            println!("{} wants to rejoin as {:?}, waiting until everyone agrees on their last commands", addr, self.peers[i].player);
+                            return;
+                        }
+                        Some(i) => {
+                            println!("{} is rejoining as {:?}", addr, self.peers[i].player);
+                            self.peers[i].rejoin(addr);
+                            self.peers[i].joined = false;
+                            i
+                        }
+                    },
+                };
+                let welcome = LobbyMessage::Welcome{player: self.peers[i].player.unwrap(), settings: self.settings.clone()};
+                send_lobby(&self.sock, self.player, addr, &welcome);
+            }
+            LobbyMessage::Ready{map_hash} => {
+                let i = match peer {
+                    Some(i) => i,
+                    None => return,
+                };
+                if map_hash != self.settings.map_hash {
+                    return self.reject(addr, format!("Your copy of {} doesn't match the host's", self.settings.level));
+                }
+                if !self.peers[i].joined {
+                    self.peers[i].joined = true;
+                    println!("Sending {} turns of history to {:?} at {}", self.history.len(), self.peers[i].player, addr);
+                    for body in history_messages(&self.history) {
+                        self.send_reliable_to(i, MESSAGE_HISTORY, &body);
+                    }
+                    self.peers[i].resend = true;
+                }
+                //we stand in for the host, and they connect to everyone else
+                let mut peers = vec![(self.player, None)];
+                peers.extend(self.peers.iter().filter_map(|p| p.player.map(|player| (player, Some(p.addr.to_string())))));
+                send_lobby(&self.sock, self.player, addr, &LobbyMessage::Start{peers, start_in_ms: 0});
+            }
+            _ => {},
+        }
+    }
+    //Someone can take a dropped peer's place once everyone still here has passed on what they have of its commands,
+    //and we're waiting on it for the next turn. Then we've played every turn it gave, and nobody has played any more.
+    fn ready_for_rejoin(&self, peer: usize) -> bool{
+        let dropped = &self.peers[peer];
+        let agreed = |j: usize, p: &Peer| j == peer || p.dropped() || p.player.map_or(false, |player| dropped.settle.get(&player) == Some(&0));
+        dropped.forwarded && dropped.received.front() != Some(&true) && self.peers.iter().enumerate().all(|(j, p)| agreed(j, p))
+    }
+    //when a peer goes quiet, pass on its commands we have to everyone else
+    fn forward_dropped(&mut self){
+        for i in 0..self.peers.len(){
+            let player = match self.peers[i].player {
+                Some(player) if !self.peers[i].relay && !self.peers[i].forwarded && self.peers[i].dropped() => player,
+                _ => continue,
+            };
+            self.peers[i].forwarded = true;
+            let turns = self.peers[i].log.iter().map(|(&turn, commands)| TurnCommands{turn, commands: commands.clone()}).collect();
+            let parts = forward_messages(player, turns);
+            println!("{:?} went quiet, passing on their commands for {} turns", player, self.peers[i].log.len());
+            for j in 0..self.peers.len(){
+                if j != i && self.peers[j].player.is_some(){
+                    for part in &parts{
+                        self.send_reliable_to(j, MESSAGE_FORWARD, part);
+                    }
+                }
+            }
+        }
+    }
+    //our commands for every sealed turn that hasn't happened yet, for a peer that rejoined
+    fn resend_commands(&mut self, peer: usize, orders: &CommandBuffer, turn: u64){
+        for t in turn..self.sent_through{
+            let slot = (t - turn) as usize;
+            if slot >= orders.len(){
+                break;
+            }
+            let message = TurnCommands{turn: t, commands: orders[slot].iter().cloned().filter(|o| o.player == self.player).collect()};
+            self.send_reliable_to(peer, MESSAGE_TURNS, &serialize(&message).unwrap());
+        }
+    }
+    //send our commands for any turns that are sealed. Only the back slot still takes new orders.
+    pub fn send_commands(&mut self, orders: &mut CommandBuffer, turn: u64){
+        let sealed_to = turn + (orders.len() as u64) - 1;
+        if self.spectating(){
+            self.sent_through = sealed_to;//we never give orders
+        }
+        while self.sent_through < sealed_to{
+            if self.peers.iter().any(|p| p.relayed.contains_key(&self.sent_through)){
+                self.sent_through += 1;//catching up after a rejoin, the server already decided it
+                continue;
+            }
+            let slot = (self.sent_through - turn) as usize;
+            let mut message = TurnCommands{turn: self.sent_through, commands: orders[slot].iter().cloned().filter(|o| o.player == self.player).collect()};
+            let mut data = serialize(&message).unwrap();
+            while data.len() > MAX_TURN_BODY{
+                //drop our own newest order everywhere, including here, so we stay in sync
+                println!("Too many orders on turn {}, dropping one", message.turn);
+                let dropped = message.commands.pop().unwrap();
+                if let Some(i) = orders[slot].iter().rposition(|o| *o == dropped){
+                    orders[slot].remove(i);
+                }
+                data = serialize(&message).unwrap();
+            }
+            println!("Sending commands for turn {}, {} bytes", message.turn, data.len());
+            self.send_reliable(MESSAGE_TURNS, &data);
+            self.sent_through += 1;
+        }
+        self.flush();
     }
-    pub fn send_commands(&mut self, orders: &CommandBuffer, turn: u64){
-        let mut buf = Vec::new();
-        buf.push(PACKET_ORDER);
-        serialize_into(&mut buf, &turn).unwrap();
-        serialize_into(&mut buf, orders).unwrap();
-        self.sock.send_to(&buf, self.target).expect("Sending failed");
-        println!("Sending Packet of size {}", buf.len());
+    //send hashes of worlds that have fallen off the left edge, for the peers to compare against
+    pub fn send_hashes(&mut self, timeline: &Timeline){
+        let hashes = self.desync.record_local(timeline);
+        if hashes.len() > 0 && !self.spectating() {
+            let buf = serialize(&hashes).unwrap();
+            self.send_to_all(PACKET_HASH, &buf);
+        }
+    }
+    //our side of a desync, for the peer we disagree with to dump next to theirs
+    pub fn send_world(&mut self, player: Player, time: ChronalTime, world: &Simulation){
+        let body = match world_message(time, world) {
+            Some(body) => body,
+            None => return,
+        };
+        if let Some(i) = self.peers.iter().position(|p| p.player == Some(player)){
+            self.send_reliable_to(i, MESSAGE_WORLD, &body);
+            self.flush();
+        }
     }
-    pub fn can_advance(&self) -> bool {
-        if self.received[0]{
+    pub fn can_advance(&mut self) -> bool {
+        let waiting: Vec<Option<Player>> = self.peers.iter().filter(|p| p.received.front() != Some(&true)).map(|p| p.player).collect();
+        if waiting.len() == 0{
             print!(".");
             true
         } else {
-            println!("connection, skipped turn");
+            println!("connection, skipped turn (waiting on {:?})", waiting);
+            self.stalls += 1;
             false
         }
     }
+    //how many turns in a row we have everything for
+    pub fn turns_ready(&self) -> usize{
+        self.peers.iter().map(|p| p.received.iter().take_while(|&&r| r).count()).min().unwrap_or(0)
+    }
     pub fn advance(&mut self) {
-        self.received.push_back(false);
-        let x = self.received.pop_front().unwrap();
-        debug_assert!(x == true);
+        for peer in &mut self.peers{
+            let x = peer.received.pop_front().unwrap();
+            debug_assert!(x == true);
+        }
+    }
+    //worst round trip time and jitter of any peer
+    fn worst_latency(&self) -> (Option<f32>, f32){
+        let mut rtt = None;
+        let mut jitter = 0.0;
+        for peer in &self.peers{
+            if let Some(peer_rtt) = peer.channel.rtt_ms{
+                if rtt.map_or(true, |r| peer_rtt+4.0*peer.channel.jitter_ms > r+4.0*jitter){
+                    rtt = Some(peer_rtt);
+                    jitter = peer.channel.jitter_ms;
+                }
+            }
+        }
+        (rtt, jitter)
+    }
+    //the command delay we'd like, so commands nearly always arrive before they're needed.
+    //A turn's commands are sent a turn after they're given, and need to arrive one way in the rest of the delay.
+    pub fn wanted_delay(&self, tick_time: u32) -> Option<usize>{
+        let (rtt, jitter) = self.worst_latency();
+        rtt.map(|rtt| {
+            let one_way = rtt/2.0 + 2.0*jitter;
+            let turns = (one_way/(tick_time as f32)).ceil() as usize;
+            min(max(turns+MIN_COMMAND_DELAY, MIN_COMMAND_DELAY), MAX_COMMAND_DELAY)
+        })
+    }
+    pub fn status(&self, command_delay: usize) -> NetStatus{
+        let (rtt_ms, jitter_ms) = self.worst_latency();
+        let quiet = |p: &Peer| p.received.front() != Some(&true) && p.dropped();
+        let dropped = self.peers.iter().filter(|p| quiet(p)).map(|p| if p.relay {Player::PASSIVE} else {p.player.unwrap_or(Player::PASSIVE)}).collect();
+        NetStatus{command_delay, stalls: self.stalls, rtt_ms, jitter_ms, dropped}
+    }
+}
+
+pub fn reliable_message(kind: u8, body: &[u8]) -> Vec<u8>{
+    let mut message = Vec::with_capacity(1+body.len());
+    message.push(kind);
+    message.extend_from_slice(body);
+    message
+}
+//every final turn so far, to re-simulate from the start
+pub fn history_messages(history: &[Vec<AchronalCommand>]) -> Vec<Vec<u8>>{
+    let mut messages = Vec::new();
+    let mut first = 0;
+    while first < history.len() {
+        let mut count = min(HISTORY_TURNS, history.len()-first);
+        let mut turns = RelayTurns{first_turn: first as u64, turns: history[first..first+count].to_vec()};
+        while serialized_size(&turns).unwrap() > MAX_BODY as u64 && count > 1 {
+            count /= 2;
+            turns.turns.truncate(count);
+        }
+        messages.push(serialize(&turns).unwrap());
+        first += count;
     }
-}
\ No newline at end of file
+    messages
+}
+//a dropped player's commands, in as many parts as it takes
+fn forward_messages(player: Player, turns: Vec<TurnCommands>) -> Vec<Vec<u8>>{
+    let header = serialized_size(&ForwardedTurns{player, parts: 0, turns: Vec::new()}).unwrap();
+    let mut parts: Vec<Vec<TurnCommands>> = vec![Vec::new()];
+    let mut size = header;
+    for turn in turns{
+        let turn_size = serialized_size(&turn).unwrap();
+        if size + turn_size > MAX_BODY as u64 && !parts.last().unwrap().is_empty(){
+            parts.push(Vec::new());
+            size = header;
+        }
+        size += turn_size;
+        parts.last_mut().unwrap().push(turn);
+    }
+    let count = parts.len() as u32;
+    parts.into_iter().map(|turns| serialize(&ForwardedTurns{player, parts: count, turns}).unwrap()).collect()
+}
+//None if the world is too large for one message
+pub fn world_message(time: ChronalTime, world: &Simulation) -> Option<Vec<u8>>{
+    let body = serialize(&(time, world)).unwrap();
+    if body.len() > MAX_BODY{
+        println!("The world at time {} is {} bytes, too large to send", time, body.len());
+        return None;
+    }
+    Some(body)
+}
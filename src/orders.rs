@@ -1,23 +1,25 @@
 use simulation::*;
 use std::collections::VecDeque;
 pub type ChronalTime = u32;
+pub const MIN_COMMAND_DELAY: usize = 2;
+pub const MAX_COMMAND_DELAY: usize = 30;
 
 //chronal events
-#[derive(Serialize, Deserialize, Debug,  PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TransportCommand{
     pub to: NodeInd,
     pub percent: u8,
 }
-#[derive(Serialize, Deserialize, Debug,  PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct SendAllCommand{
     pub to: Option<NodeInd>,
 }
-#[derive(Serialize, Deserialize, Debug,  PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ChronalCommandTypes{
     Transport(TransportCommand),
     SendAll(SendAllCommand),
 }
-#[derive(Serialize, Deserialize, Debug,  PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ChronalCommand{
     pub time: ChronalTime,
     pub target: Option<NodeInd>,
@@ -26,19 +28,20 @@ pub struct ChronalCommand{
 }
 
 //achronal events
-#[derive(Serialize, Deserialize, Debug,  PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ClearCommand{
     pub time: ChronalTime,
     pub target: NodeInd,
 }
 
-#[derive(Serialize, Deserialize, Debug,  PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum AchronalCommandTypes{
     Chronal(ChronalCommand),
     Timejump(ChronalTime), //gives a time directly, no backing struct
-    ClearCommands(ClearCommand)
+    ClearCommands(ClearCommand),
+    CommandDelay(usize), //the command delay this player wants, the game uses the largest. Not part of the timeline.
 }
-#[derive(Serialize, Deserialize, Debug,  PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct AchronalCommand{
     pub player: Player,
     pub event: AchronalCommandTypes,
@@ -0,0 +1,312 @@
+use simulation::*;
+use timeline::*;
+use networking::*;
+use transport::*;
+use desync::*;
+use lobby::*;
+use std::io::ErrorKind;
+use std::net::*;
+use std::time::{Duration, Instant};
+use std::collections::BTreeMap;
+use bincode::*;
+
+struct RelayClient{
+    player: Player, //PASSIVE for a spectator, who only gets sent the turns
+    addr: SocketAddrV4,
+    channel: Channel,
+    commands: BTreeMap<u64, Vec<AchronalCommand>>, //sent to us, but not final yet
+    last_heard: Instant,
+    joined: bool, //false while someone joining late is still loading the map
+}
+impl RelayClient{
+    fn new(player: Player, addr: SocketAddrV4, joined: bool) -> RelayClient{
+        RelayClient{player, addr, channel: Channel::new(), commands: BTreeMap::new(), last_heard: Instant::now(), joined}
+    }
+    //then someone else can rejoin in their place
+    fn dropped(&self) -> bool{
+        Instant::now() - self.last_heard > Duration::from_millis(DROP_MS)
+    }
+}
+
+//A dedicated server. Clients only talk to it. It collects each client's commands for a turn,
+//and once everyone's are in (or the turn is due) sends the final set to everyone. Late commands are dropped.
+//It runs its own timeline to judge victory and to tell who desynced.
+//Every final turn is kept, so someone rejoining (or spectating late) can re-simulate the game so far.
+//That's far smaller to send than the timeline itself.
+pub struct Relay{
+    sock: UdpSocket,
+    clients: Vec<RelayClient>,
+    next_turn: u64, //the next turn to finalize
+    first_seen: BTreeMap<u64, Instant>, //when the first commands for each turn came in
+    settings: GameSettings,
+    history: Vec<Vec<AchronalCommand>>, //every final turn so far
+    pub timeline: Timeline,
+    pub desync: DesyncChecker,
+    pub late: u32, //commands that came in after their turn was final
+}
+
+impl Relay{
+    pub fn new(lobby: LobbyResult, timeline: Timeline) -> Relay{
+        let clients = lobby.peers.iter().map(|p| RelayClient::new(p.player, p.addr, true)).collect();
+        //clients start with command_delay empty turns, the last of which they still fill in
+        let next_turn = (lobby.settings.command_delay - 1) as u64;
+        let history = vec![Vec::new(); next_turn as usize];
+        Relay{sock: lobby.sock, clients, next_turn, first_seen: BTreeMap::new(), settings: lobby.settings, history, timeline, desync: DesyncChecker::new(), late: 0}
+    }
+    fn send_to(&self, addr: SocketAddrV4, kind: u8, data: &[u8]){
+        let mut buf = Vec::with_capacity(2+data.len());
+        buf.push(kind);
+        buf.push(Player::PASSIVE as u8);
+        buf.extend_from_slice(data);
+        if let Err(e) = self.sock.send_to(&buf, addr){
+            println!("Sending to {} failed: {}", addr, e);
+        }
+    }
+    fn send_turns(&mut self, i: usize, turns: &RelayTurns){
+        let data = serialize(turns).unwrap();
+        self.send_reliable(i, MESSAGE_TURNS, &data);
+    }
+    fn send_reliable(&mut self, i: usize, kind: u8, body: &[u8]){
+        let addr = self.clients[i].addr;
+        for fragment in self.clients[i].channel.send(&reliable_message(kind, body)) {
+            self.send_to(addr, PACKET_DATA, &fragment);
+        }
+    }
+    fn receive(&mut self){
+        let mut buf = [0; PACKET_SIZE];
+        loop {
+            let (number_of_bytes, src_addr) = match self.sock.recv_from(&mut buf) {
+                Ok((n, SocketAddr::V4(src_addr))) => (n, src_addr),
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    println!("encountered IO error: {}", e);
+                    continue;
+                }
+            };
+            if number_of_bytes >= 2 && buf[0] == PACKET_LOBBY {
+                self.receive_lobby(src_addr, &buf[2..number_of_bytes]);
+                continue;
+            }
+            let i = match self.clients.iter().position(|c| c.addr == src_addr) {
+                Some(i) if number_of_bytes >= 2 => i,
+                _ => {
+                    println!("Packet from {}, which isn't a client", src_addr);
+                    continue;
+                }
+            };
+            self.clients[i].last_heard = Instant::now();
+            let data = &buf[2..number_of_bytes];
+            let spectator = self.clients[i].player == Player::PASSIVE;
+            match buf[0] {
+                PACKET_DATA if spectator => {},//spectators have nothing to say
+                PACKET_DATA => {
+                    match self.clients[i].channel.receive_fragment(data) {
+                        Ok(Some(message)) => self.receive_message(i, &message),
+                        Ok(None) => {},
+                        Err(e) => println!("Bad fragment from {}: {}", src_addr, e),
+                    }
+                }
+                PACKET_ACK => {
+                    if let Err(e) = self.clients[i].channel.receive_acks(data) {
+                        println!("Bad ack from {}: {}", src_addr, e);
+                    }
+                }
+                PACKET_HASH if !spectator => {
+                    match config().limit(PACKET_SIZE as u64).deserialize::<Vec<(ChronalTime, u64)>>(data) {
+                        Ok(hashes) => self.desync.record_remote(self.clients[i].player, &hashes),
+                        Err(e) => println!("Bad hash packet from {}: {}", src_addr, e),
+                    }
+                }
+                _ => {},//connection requests
+            }
+        }
+    }
+    //someone joining after the start: a spectator, or a player taking back a slot whose client went quiet
+    fn receive_lobby(&mut self, addr: SocketAddrV4, data: &[u8]){
+        let message = match parse_lobby(data) {
+            Ok(message) => message,
+            Err(e) => {
+                println!("Bad lobby packet from {}: {}", addr, e);
+                return;
+            }
+        };
+        let client = self.clients.iter().position(|c| c.addr == addr);
+        match message {
+            LobbyMessage::Hello{protocol, build, spectator} => {
+                if let Some(reason) = hello_problem(protocol, build) {
+                    send_lobby(&self.sock, Player::PASSIVE, addr, &LobbyMessage::Reject{reason});
+                    return;
+                }
+                let i = match client {
+                    Some(i) => i,//they missed the welcome
+                    None if spectator => {
+                        println!("{} joined late as a spectator", addr);
+                        self.clients.push(RelayClient::new(Player::PASSIVE, addr, false));
+                        self.clients.len()-1
+                    }
+                    None => match self.clients.iter().position(|c| c.player != Player::PASSIVE && c.dropped()) {
+                        Some(i) => {
+                            println!("{} is rejoining as {:?}", addr, self.clients[i].player);
+                            self.clients[i] = RelayClient::new(self.clients[i].player, addr, false);
+                            i
+                        }
+                        None => {
+                            let reason = "The game has already started, and nobody has dropped".to_string();
+                            send_lobby(&self.sock, Player::PASSIVE, addr, &LobbyMessage::Reject{reason});
+                            return;
+                        }
+                    },
+                };
+                let welcome = LobbyMessage::Welcome{player: self.clients[i].player, settings: self.settings.clone()};
+                send_lobby(&self.sock, Player::PASSIVE, addr, &welcome);
+            }
+            LobbyMessage::Ready{map_hash} => {
+                let i = match client {
+                    Some(i) => i,
+                    None => return,
+                };
+                if map_hash != self.settings.map_hash {
+                    let reason = format!("Your copy of {} doesn't match the host's", self.settings.level);
+                    send_lobby(&self.sock, Player::PASSIVE, addr, &LobbyMessage::Reject{reason});
+                    return;
+                }
+                if !self.clients[i].joined {
+                    self.clients[i].joined = true;
+                    self.send_history(i);
+                }
+                //also covers anyone who missed the countdown
+                let start = LobbyMessage::Start{peers: vec![(Player::PASSIVE, None)], start_in_ms: 0};
+                send_lobby(&self.sock, Player::PASSIVE, addr, &start);
+            }
+            _ => {},
+        }
+    }
+    //every final turn so far, to re-simulate from the start
+    fn send_history(&mut self, i: usize){
+        println!("Sending {} turns of history to {:?} at {}", self.history.len(), self.clients[i].player, self.clients[i].addr);
+        for body in history_messages(&self.history) {
+            self.send_reliable(i, MESSAGE_TURNS, &body);
+        }
+    }
+    fn receive_message(&mut self, i: usize, message: &[u8]){
+        let player = self.clients[i].player;
+        match message.split_first() {
+            Some((&MESSAGE_TURNS, body)) => self.receive_commands(i, body),
+            Some((&MESSAGE_WORLD, body)) => match config().limit(MAX_MESSAGE as u64).deserialize::<(ChronalTime, Simulation)>(body) {
+                Ok((time, world)) => write_dump(Player::PASSIVE, self.timeline.final_state(time), player, time, &world),
+                Err(e) => println!("Bad world from {:?}: {}", player, e),
+            },
+            _ => println!("Unknown message from {:?}", player),
+        }
+    }
+    fn receive_commands(&mut self, i: usize, message: &[u8]){
+        let client = &mut self.clients[i];
+        let rec: TurnCommands = match config().limit(MAX_MESSAGE as u64).deserialize(message) {
+            Ok(rec) => rec,
+            Err(e) => {
+                println!("Bad commands from {:?}: {}", client.player, e);
+                return;
+            }
+        };
+        if rec.turn < self.next_turn {
+            println!("{:?}'s commands for turn {} came in too late", client.player, rec.turn);
+            self.late += 1;
+            return;
+        }
+        //a client can't be ahead of the last final turn, so that's as far as its command delay reaches
+        if rec.turn >= self.next_turn + MAX_COMMAND_DELAY as u64 {
+            println!("{:?} sent commands for turn {}, too far ahead of turn {}", client.player, rec.turn, self.next_turn);
+            return;
+        }
+        let player = client.player;
+        client.commands.insert(rec.turn, rec.commands.into_iter().filter(|o| o.player == player).collect());
+        self.first_seen.entry(rec.turn).or_insert(Instant::now());
+    }
+    //the clock is set by whoever is on time. Everyone gets the minimum command delay to catch up,
+    //after that the others would start stalling.
+    fn turn_due(&self, turn: u64) -> Option<Instant>{
+        let grace = Duration::from_millis(self.settings.tick_time as u64 * MIN_COMMAND_DELAY as u64);
+        self.first_seen.get(&turn).map(|&seen| seen + grace)
+    }
+    //nobody waits on spectators, anyone still loading, or anyone who dropped
+    fn waiting_on_someone(&self, turn: u64) -> bool{
+        self.clients.iter().any(|c| c.player != Player::PASSIVE && c.joined && !c.dropped() && !c.commands.contains_key(&turn))
+    }
+    fn finalize(&mut self, turn: u64){
+        let mut commands = Vec::new();
+        for client in &mut self.clients {
+            match client.commands.remove(&turn) {
+                Some(c) => commands.extend(c),
+                None if client.player == Player::PASSIVE => {},
+                None => println!("{:?} missed turn {}", client.player, turn),
+            }
+        }
+        commands.sort_by_key(|o| o.player);
+        let mut turns = RelayTurns{first_turn: turn, turns: vec![commands]};
+        while serialized_size(&turns).unwrap() > MAX_BODY as u64 {
+            println!("Too many orders on turn {}, dropping one", turn);
+            turns.turns[0].pop();
+        }
+        for i in 0..self.clients.len() {
+            if self.clients[i].joined {
+                self.send_turns(i, &turns);
+            }
+        }
+        let commands = turns.turns.pop().unwrap();
+        self.history.push(commands.clone());
+        self.timeline.evaluate_timestep(commands);
+        let hashes = self.desync.record_local(&self.timeline);
+        if hashes.len() > 0 {
+            let buf = serialize(&hashes).unwrap();
+            for client in &self.clients {
+                if client.joined {
+                    self.send_to(client.addr, PACKET_HASH, &buf);
+                }
+            }
+        }
+    }
+    fn flush(&mut self){
+        for i in 0..self.clients.len() {
+            let addr = self.clients[i].addr;
+            let mut packets: Vec<(u8, Vec<u8>)> = Vec::new();
+            for fragment in self.clients[i].channel.resend() {
+                packets.push((PACKET_DATA, fragment));
+            }
+            for ack in self.clients[i].channel.take_acks() {
+                packets.push((PACKET_ACK, ack));
+            }
+            for (kind, data) in packets {
+                self.send_to(addr, kind, &data);
+            }
+        }
+    }
+    //call often. Returns the first desync found, once.
+    pub fn update(&mut self) -> Option<Desync>{
+        self.receive();
+        //if everyone has dropped, the game waits for someone to come back
+        loop {
+            let turn = self.next_turn;
+            let everyone_in = self.first_seen.contains_key(&turn) && !self.waiting_on_someone(turn);
+            let overdue = self.turn_due(turn).map_or(false, |due| Instant::now() >= due);
+            if everyone_in || overdue {
+                self.first_seen.remove(&turn);
+                self.finalize(turn);
+                self.next_turn += 1;
+            } else {
+                break;
+            }
+        }
+        let desync = self.desync.check();
+        //the client gets our world to dump next to theirs
+        if let Some(d) = desync {
+            let client = self.clients.iter().position(|c| c.player == d.peer);
+            let body = self.timeline.final_state(d.time).and_then(|world| world_message(d.time, &world));
+            if let (Some(i), Some(body)) = (client, body) {
+                self.send_reliable(i, MESSAGE_WORLD, &body);
+            }
+        }
+        self.flush();
+        desync
+    }
+}
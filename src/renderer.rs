@@ -4,6 +4,10 @@ use simulation::*;
 use timeline::*;
 use interface::*;
 use library::*;
+use library::Point2;
+use font::*;
+use victory::*;
+use networking::NetStatus;
 use std::f32::consts::PI;
 
 struct GlobalResources{
@@ -23,10 +27,24 @@ impl GlobalResources{
 }
 
 fn set_col(ctx: &mut Context, conf: &InterfaceConfig, player: Player) -> GameResult<()>{
-    let col = conf.colors[player as usize];
+    let col = conf.colors.get(player as usize).cloned().unwrap_or(0xFFFFFF);
     set_color(ctx, Color::from_rgb_u32(col))?;
     Ok(())
 }
+//for last-known values under the fog
+fn set_col_faded(ctx: &mut Context, conf: &InterfaceConfig, player: Player) -> GameResult<()>{
+    let col = conf.colors.get(player as usize).cloned().unwrap_or(0xFFFFFF);
+    let mut color = Color::from_rgb_u32(col);
+    color.a = 0.4;
+    set_color(ctx, color)?;
+    Ok(())
+}
+//how many ticks a change made by rewriting history stays highlighted
+const CHANGE_FADE: f32 = 100.;
+//how strongly a change seen at this present is highlighted, from 1 down to 0
+fn change_fade(changes: &Changes, present: ChronalTime) -> f32{
+    (1. - (present - changes.seen) as f32/CHANGE_FADE).max(0.)
+}
 pub struct Renderer{
     resources: GlobalResources
 }
@@ -35,8 +53,21 @@ impl Renderer {
         let resources = GlobalResources::new(ctx)?;
         Ok(Renderer{resources})
     }
-    pub fn render(&self, ctx: &mut Context, conf: &InterfaceConfig, viewing_player: Player, timeline: &Timeline, interface: &GameInterface, dt: f32) -> GameResult<()> {
-        let sim = &timeline[viewing_player];
+    pub fn render(&self, ctx: &mut Context, conf: &InterfaceConfig, viewing_player: Player, timeline: &Timeline, interface: &GameInterface, net: Option<&NetStatus>, dt: f32) -> GameResult<()> {
+        //looking at another time from the timeline, without jumping there
+        let preview = interface.preview.and_then(|time| timeline.preview(time));
+        let sim = preview.as_ref().unwrap_or(&timeline[viewing_player]);
+        let fog = interface.fog.as_ref();
+        let preview_visible = match (fog, preview.as_ref()) {
+            (Some(_), Some(world)) => Some(world.visibility(viewing_player)),
+            _ => None,
+        };
+        let visible = preview_visible.as_ref().or(fog.map(|f| &f.visible));
+        let lane_visible = |edge| visible.map_or(true, |v| v.lane(edge));
+        let planet_visible = |node| visible.map_or(true, |v| v.planet(node));
+        //with fog of war, only changes involving your team are marked on the timeline
+        let change_shown = |change: &PlanetChange| fog.is_none() || viewing_player == Player::PASSIVE || change.involves(viewing_player, sim);
+        let changes_here = timeline.changes(sim.timestep).map(|c| (c, change_fade(c, timeline.present)));
         //transform from scrolling
         let screen = |loc| {(loc-interface.center_loc)};
 
@@ -46,11 +77,51 @@ impl Renderer {
             let t = &sim.world[edge_ref.target()];
             let s_loc = screen(gpt(s.loc));
             let t_loc = screen(gpt(t.loc));
-            set_color(ctx, Color::from_rgba(255, 255, 255, 255))?;
-            line(ctx, &[s_loc, t_loc], 2.)?;
             let edge = edge_ref.weight();
+            //fast lanes are blue, slow ones brown
+            if edge.speed > 100 {
+                set_color(ctx, Color::from_rgba(128, 192, 255, 255))?;
+            } else if edge.speed < 100 {
+                set_color(ctx, Color::from_rgba(192, 128, 64, 255))?;
+            } else {
+                set_color(ctx, Color::from_rgba(255, 255, 255, 255))?;
+            }
+            line(ctx, &[s_loc, t_loc], 2.)?;
+            let middle = s_loc + (t_loc - s_loc) * 0.5;
+            if let Some(dir) = edge.one_way {
+                let angle = match dir {
+                    DIR::FORWARD => pt_dir(&s_loc, &t_loc),
+                    DIR::BACKWARD => pt_dir(&t_loc, &s_loc),
+                };
+                line(ctx, &[middle+lendir(-12., angle+PI/5.), middle, middle+lendir(-12., angle-PI/5.)], 2.)?;
+            }
+            if let Some(capacity) = edge.capacity {
+                let label_loc = middle+lendir(16., pt_dir(&s_loc, &t_loc)+PI/2.);
+                set_color(ctx, Color::from_rgba(255, 255, 255, 255))?;
+                circle(ctx, DrawMode::Fill, label_loc, 10., 0.25)?;
+                set_color(ctx, Color::from_rgba(0, 0, 0, 255))?;
+                self.resources.small_num_font.draw_centered(ctx, label_loc, capacity.to_string())?;
+            }
+            //armies on a lane can be seen from the lane or from either end of it
+            if !lane_visible(edge_ref.id()) && !planet_visible(edge_ref.source()) && !planet_visible(edge_ref.target()) {
+                continue;
+            }
+            //armies that met on the lane leave a burst where they fought, growing and fading over CLASH_SHOW ticks
+            for clash in &edge.clashes {
+                let age = ((sim.timestep - clash.time) as f32 + dt)/(CLASH_SHOW as f32);
+                let loc = s_loc + (t_loc - s_loc) * (clash.at as f32/edge.length as f32);
+                let size = 8.+(clash.dead as f32).log2()*2.;
+                let alpha = (255.*(1.-age).max(0.)) as u8;
+                set_color(ctx, Color::from_rgba(255, 128, 0, alpha))?;
+                for i in 0..8 {
+                    let angle = (i as f32)*PI/4.;
+                    line(ctx, &[loc+lendir(size*(0.5+age), angle), loc+lendir(size*(1.+age), angle)], 2.)?;
+                }
+                circle(ctx, DrawMode::Fill, loc, size*0.4, 0.25)?;
+            }
             for group in &edge.transfers {
-                let future_progress = ((group.progress as f32)+((ARMY_SPEED as f32)*dt))/(edge.length as f32);
+                let step = edge.step()*group.speed/100;
+                let future_progress = ((group.progress as f32)+((step as f32)*dt))/(edge.length as f32);
                 let vis_progress = match group.direction {
                     DIR::FORWARD => future_progress,
                     DIR::BACKWARD => 1.0 - future_progress
@@ -69,26 +140,59 @@ impl Renderer {
         for node_ind in sim.world.node_indices() {
             let node = &sim.world[node_ind];
             let node_loc = screen(gpt(node.loc));
+            let radius = node.max_strength as f32;
 
+            //production and defense are part of the map, so they show under the fog too
+            if node.defense != 100 {
+                //fortresses get a heavy grey wall, weak worlds a thin red one
+                let (color, width) = if node.defense > 100 {
+                    (Color::from_rgba(96, 96, 96, 255), 2.+((node.defense-100) as f32)/25.)
+                } else {
+                    (Color::from_rgba(255, 64, 64, 255), 1.)
+                };
+                set_color(ctx, color)?;
+                circle(ctx, DrawMode::Line(width), node_loc, radius+4.+width/2., 0.25)?;
+            }
+            if node.production*SPAWN_NEEDED != PRODUCTION*node.spawn_needed {
+                //a dot per half the normal production rate
+                let rate = (node.production as f32/node.spawn_needed as f32)/(PRODUCTION as f32/SPAWN_NEEDED as f32);
+                let pips = (rate*2.).round().max(1.).min(8.) as usize;
+                set_color(ctx, Color::from_rgba(255, 215, 0, 255))?;
+                for i in 0..pips {
+                    let x = ((i as f32)-((pips-1) as f32)/2.)*8.;
+                    circle(ctx, DrawMode::Fill, pt(node_loc.x+x, node_loc.y+radius+12.), 3., 0.25)?;
+                }
+            }
+
+            let lanes = sim.world.neighbors(node_ind).map(|n| pt_dir(&node_loc, &screen(gpt(sim.world[n].loc)))).collect();
+            self.draw_kind(ctx, node.kind, node_loc, radius, &lanes)?;
+
+            if !planet_visible(node_ind) {
+                //hidden, so draw whatever was last seen there
+                set_color(ctx, Color::from_rgba(128, 128, 128, 255))?;
+                circle(ctx, DrawMode::Fill, node_loc, radius, 0.25)?;
+                if let Some(seen) = fog.and_then(|f| f.remembered(node_ind, sim.timestep)) {
+                    set_col_faded(ctx, conf, seen.owner)?;
+                    circle(ctx, DrawMode::Line(5.0), node_loc, radius, 0.25)?;
+                    let involved: Vec<Player> = Player::values().filter(|&p| seen.count[p] > 0).collect();
+                    self.draw_counts(ctx, conf, node_loc, &involved, &seen.count, true)?;
+                }
+                continue;
+            }
             set_color(ctx, Color::from_rgba(255, 255, 255, 255))?;
-            circle(ctx, DrawMode::Fill, node_loc, node.max_strength as f32, 0.25)?;
+            circle(ctx, DrawMode::Fill, node_loc, radius, 0.25)?;
             set_col(ctx, conf, node.owner)?;
             circle(ctx, DrawMode::Line(5.0), node_loc, node.owner_strength as f32, 0.25)?;
 
             let involved = find_sides_node(node);
-            if involved.len() == 1 {
-                let player = involved[0];
-                set_col(ctx, conf, player)?;
-                self.resources.num_font.draw_centered(ctx, node_loc, node.count[player].to_string())?;
-            } else if involved.len() > 1 {
-                let count = involved.len() as f32;
-                let angle_increment = 2.0*PI/count;
-                let mut angle = PI/2.0;
-                for player in involved{
-                    set_col(ctx, conf, player)?;
-                    let loc = node_loc+lendir(16.0, angle);
-                    self.resources.num_font.draw_centered(ctx, loc, node.count[player].to_string()) ?;
-                    angle += angle_increment;
+            self.draw_counts(ctx, conf, node_loc, &involved, &node.count, false)?;
+
+            //rewritten by a timewave not long ago
+            if let Some((changes, fade)) = changes_here {
+                if let Some(change) = changes.planets.iter().find(|c| c.node == node_ind) {
+                    let width = if change.owner.is_some() {6.0} else {3.0};
+                    set_color(ctx, Color::new(1.0, 0.0, 1.0, fade))?;
+                    circle(ctx, DrawMode::Line(width), node_loc, radius + 8., 0.25)?;
                 }
             }
 
@@ -135,12 +239,9 @@ impl Renderer {
             let mut left_edge = timeline.left_edge as f32;
             let mut right_edge = timeline.right_edge as f32;
             let present = (timeline.present as f32)+dt;
-            if present < 1000.0 {
-                right_edge += 2.0*dt;
-            } else {
-                right_edge += 1.0*dt;
-                left_edge += 1.0*dt;
-            }
+            let (left_speed, right_speed) = timeline.edge_speeds(present);
+            left_edge += left_speed*dt;
+            right_edge += right_speed*dt;
             //time ticks (every 5 seconds)
             set_color(ctx, Color::from_rgba(0, 0, 0, 128))?;
             const TICK_SIZE: f32 = 50.;
@@ -181,12 +282,34 @@ impl Renderer {
             }
             let y_mult = ui_height/(largest_living as f32);
             for player in Player::values(){
+                if fog.is_some() && viewing_player != Player::PASSIVE && !sim.allied(player, viewing_player) {
+                    continue;//enemy army sizes are hidden
+                }
                 let data = &line_data[player];
                 set_col(ctx, conf, player)?;
                 let pt_func = |(index, value)|pt(index as f32, height-(value*y_mult));
                 let line_graph = data.iter().enumerate().map(pt_func).collect::<Vec<Point2>>();
                 line(ctx, line_graph.as_slice(),2.)?;
             }
+            //change density: how much recent history rewrites changed at each time
+            let mut density = vec![0.0f32; conf.width as usize];
+            for time in timeline.left_edge..timeline.right_edge {
+                if let Some(changes) = timeline.changes(time) {
+                    let fade = change_fade(changes, timeline.present);
+                    let shown = changes.planets.iter().filter(|c| change_shown(c)).count();
+                    let x_pos = (progress(time as f32, left_edge, right_edge)*width) as usize;
+                    if fade > 0. && x_pos < density.len() {
+                        density[x_pos] += shown as f32*fade;
+                    }
+                }
+            }
+            set_color(ctx, Color::from_rgba(255, 0, 255, 160))?;
+            for (x_pos, &amount) in density.iter().enumerate() {
+                if amount > 0. {
+                    let marker = (amount*4.).min(ui_height*0.5);
+                    line(ctx, &[pt(x_pos as f32, upper_edge), pt(x_pos as f32, upper_edge+marker)], 2.)?;
+                }
+            }
             //timewaves (normal)
             set_color(ctx, Color::from_rgba(0, 0, 0, 128))?;
             for wave in &timeline.timewaves{
@@ -202,29 +325,190 @@ impl Renderer {
                 let x_pos = progress(time, left_edge, right_edge)*width;
                 line(ctx, &[pt(x_pos, upper_edge),pt(x_pos, height)],2.)?;
             }
+            //previewed time, and what jumping there would cost
+            if let Some(ref world) = preview {
+                let x_pos = progress(world.timestep as f32, left_edge, right_edge)*width;
+                set_color(ctx, Color::from_rgba(255, 255, 255, 255))?;
+                line(ctx, &[pt(x_pos, upper_edge), pt(x_pos, height)], 1.)?;
+                let energy = timeline.chrono_energy[viewing_player];
+                let afford = |cost| if cost <= energy {format!("{}", cost)} else {format!("{} (not enough)", cost)};
+                let mut label = format!("costs {}", afford(timeline.chrono_cost(world.timestep)));
+                if timeline.config.jump_cost > 0 {
+                    label = format!("jump {}, orders {}", afford(timeline.config.jump_cost), label);
+                }
+                let text = Text::new(ctx, &label, &self.resources.font)?;
+                let x_text = (x_pos+4.).min(width-(text.width() as f32));
+                graphics::draw(ctx, &text, pt(x_text, upper_edge_bar-(text.height() as f32)), 0.0)?;
+            }
             //chronoenergy display
+            let max_energy = timeline.config.max_energy as f32;
             let energy = timeline.chrono_energy[viewing_player];
             let limit = timeline.chrono_energy_limit(energy);
             let x_limit = progress(limit as f32, left_edge, right_edge)*width;
             set_color(ctx, Color::from_rgba(255, 255, 0, 64))?;
             rectangle(ctx, DrawMode::Fill, Rect::new(0.,upper_edge, x_limit, ui_height))?;//not enough energy area
 
-            let storage_x = ((energy as f32)/(MAX_CHRONOENERGY as f32))*width;
+            let storage_x = ((energy as f32)/max_energy)*width;
             set_color(ctx, Color::from_rgba(255, 255, 0, 255))?;
             rectangle(ctx, DrawMode::Fill, Rect::new(0.,upper_edge_bar, storage_x, energy_bar_height))?;
 
             let action_cost = timeline.chrono_cost(timeline.player_timewaves[viewing_player].time);
             if energy > action_cost {
-                let per_action_x = (((energy-action_cost) as f32) / (MAX_CHRONOENERGY as f32)) * width;
+                let per_action_x = (((energy-action_cost) as f32) / max_energy) * width;
                 set_color(ctx, Color::from_rgba(255, 128, 0, 255))?;
                 rectangle(ctx, DrawMode::Fill, Rect::new(0.,upper_edge_bar, per_action_x, energy_bar_height))?;
             }
+            //what a timejump takes out of the bar
+            if timeline.config.jump_cost > 0 {
+                let jump_x = ((timeline.config.jump_cost as f32)/max_energy)*width;
+                set_color(ctx, Color::from_rgba(0, 0, 0, 255))?;
+                line(ctx, &[pt(jump_x, upper_edge_bar), pt(jump_x, upper_edge)], 2.)?;
+            }
             //percentage display
             let font_height = self.resources.num_font.maxh;
             set_color(ctx, Color::from_rgba(255, 255, 255, 255))?;
             self.resources.num_font.draw(ctx, pt(0., upper_edge_bar-font_height), format!("{}%",interface.send_percent))?;
+
+            //every player at once: where their timewave is and their chrono energy
+            if interface.overlay {
+                const BAR_WIDTH: f32 = 150.;
+                let x = width-BAR_WIDTH-4.;
+                let mut y = 4.;
+                for team in timeline.victory.team_list() {
+                    for player in timeline.victory.team_members(team) {
+                        let energy = timeline.chrono_energy[player];
+                        set_color(ctx, Color::from_rgba(64, 64, 64, 255))?;
+                        rectangle(ctx, DrawMode::Fill, Rect::new(x, y, BAR_WIDTH, energy_bar_height))?;
+                        set_col(ctx, conf, player)?;
+                        let energy_x = ((energy as f32)/max_energy)*BAR_WIDTH;
+                        rectangle(ctx, DrawMode::Fill, Rect::new(x, y, energy_x, energy_bar_height))?;
+                        let time = timeline.player_timewaves[player].time as f32/600.;
+                        let watching = if player == viewing_player {" (watching)"} else {""};
+                        let label = format!("{:?}{} at {}:{:02}", player, watching, time.trunc(), (time.fract()*60.).trunc());
+                        let text = Text::new(ctx, &label, &self.resources.font)?;
+                        graphics::draw(ctx, &text, pt(x-(text.width() as f32)-8., y), 0.0)?;
+                        y += (text.height() as f32).max(energy_bar_height)+4.;
+                    }
+                }
+            }
+        }
+        //network status
+        if let Some(net) = net {
+            let rtt = match net.rtt_ms {
+                Some(rtt) => format!("{:.0}ms (jitter {:.0}ms)", rtt, net.jitter_ms),
+                None => "?".to_string(),
+            };
+            let status = format!("delay {} turns, ping {}, {} stalls", net.command_delay, rtt, net.stalls);
+            let text = Text::new(ctx, &status, &self.resources.font)?;
+            set_color(ctx, Color::from_rgba(255, 255, 255, 255))?;
+            graphics::draw(ctx, &text, pt(4., 4.), 0.0)?;
+            if net.dropped.len() > 0 {
+                let warning = if net.dropped.contains(&Player::PASSIVE) {
+                    "Lost contact with the server, waiting for it".to_string()
+                } else {
+                    format!("Waiting on {:?}, who went quiet. If their game closed, they can rejoin with chronox join and the address of anyone still playing.", net.dropped)
+                };
+                let text = Text::new(ctx, &warning, &self.resources.font)?;
+                set_color(ctx, Color::from_rgba(255, 128, 0, 255))?;
+                graphics::draw(ctx, &text, pt(4., 8.+(text.height() as f32)), 0.0)?;
+            }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+    //a mark for each special kind of planet, given the directions of its lanes
+    fn draw_kind(&self, ctx: &mut Context, kind: PlanetKind, node_loc: Point2, radius: f32, lanes: &Vec<f32>) -> GameResult<()> {
+        match kind {
+            PlanetKind::Normal => {},
+            PlanetKind::Shipyard => {
+                //docks around the rim
+                set_color(ctx, Color::from_rgba(96, 128, 192, 255))?;
+                for i in 0..4 {
+                    let dock = node_loc+lendir(radius+6., PI/4.+(i as f32)*PI/2.);
+                    rectangle(ctx, DrawMode::Fill, Rect::new(dock.x-5., dock.y-5., 10., 10.))?;
+                }
+            }
+            PlanetKind::Fortress => {
+                //walls
+                set_color(ctx, Color::from_rgba(64, 64, 64, 255))?;
+                let corners: Vec<Point2> = (0..8).map(|i| node_loc+lendir(radius+6., (i as f32)*PI/4.+PI/8.)).collect();
+                polygon(ctx, DrawMode::Line(4.0), &corners)?;
+            }
+            PlanetKind::Relay => {
+                //arrows pointing out along each lane
+                set_color(ctx, Color::from_rgba(0, 192, 192, 255))?;
+                for &angle in lanes {
+                    let tip = node_loc+lendir(radius+14., angle);
+                    line(ctx, &[tip+lendir(-8., angle+PI/4.), tip, tip+lendir(-8., angle-PI/4.)], 3.)?;
+                }
+            }
+            PlanetKind::ChronoBeacon => {
+                //the chrono energy colour, with a diamond on top
+                set_color(ctx, Color::from_rgba(255, 255, 0, 255))?;
+                circle(ctx, DrawMode::Line(2.0), node_loc, radius+4., 0.25)?;
+                let top = node_loc+lendir(radius+12., -PI/2.);
+                let diamond = [top+lendir(6., 0.), top+lendir(6., PI/2.), top+lendir(6., PI), top+lendir(6., -PI/2.)];
+                polygon(ctx, DrawMode::Fill, &diamond)?;
+            }
+        }
+        Ok(())
+    }
+    fn draw_counts(&self, ctx: &mut Context, conf: &InterfaceConfig, node_loc: Point2, involved: &Vec<Player>, count: &PlayerArr<u32>, faded: bool) -> GameResult<()> {
+        let set = |ctx: &mut Context, player| if faded {set_col_faded(ctx, conf, player)} else {set_col(ctx, conf, player)};
+        if involved.len() == 1 {
+            let player = involved[0];
+            set(ctx, player)?;
+            self.resources.num_font.draw_centered(ctx, node_loc, count[player].to_string())?;
+        } else if involved.len() > 1 {
+            let num = involved.len() as f32;
+            let angle_increment = 2.0*PI/num;
+            let mut angle = PI/2.0;
+            for &player in involved{
+                set(ctx, player)?;
+                let loc = node_loc+lendir(16.0, angle);
+                self.resources.num_font.draw_centered(ctx, loc, count[player].to_string()) ?;
+                angle += angle_increment;
+            }
+        }
+        Ok(())
+    }
+    pub fn render_results(&self, ctx: &mut Context, conf: &InterfaceConfig, viewing_player: Player, timeline: &Timeline, result: &MatchResult) -> GameResult<()> {
+        let title = if result.winners.is_empty() {
+            "Draw".to_string()
+        } else if viewing_player == Player::PASSIVE {
+            format!("{:?} won", result.winners)
+        } else if result.winners.contains(&viewing_player) {
+            "Victory!".to_string()
+        } else {
+            format!("Defeat - {:?} wins", result.winners)
+        };
+        let reason = match result.condition {
+            VictoryCondition::Elimination => "by elimination".to_string(),
+            VictoryCondition::Domination{planets, ticks} => format!("by holding {} planets for {} ticks", planets, ticks),
+            VictoryCondition::TimeLimit(_) => "by most units alive at the time limit".to_string(),
+        };
+        let mut lines = vec![title, reason, format!("decided at time {}", result.time)];
+        for team in timeline.victory.team_list() {
+            for player in timeline.victory.team_members(team) {
+                lines.push(format!("{:?} (team {}): {} alive, {} planets", player, team, result.metadata.total_living[player], result.metadata.planets_owned[player]));
+            }
+        }
+        self.render_lines(ctx, conf, &lines, result.winners.first().cloned())
+    }
+    //centered lines of text, with the first in a player's color
+    pub fn render_lines(&self, ctx: &mut Context, conf: &InterfaceConfig, lines: &Vec<String>, title_player: Option<Player>) -> GameResult<()> {
+        let width = conf.width as f32;
+        let mut y = 150.0;
+        for (i, text_str) in lines.iter().enumerate() {
+            let text = Text::new(ctx, text_str, &self.resources.font)?;
+            match title_player {
+                Some(p) if i == 0 => set_col(ctx, conf, p)?,
+                _ => set_color(ctx, Color::from_rgba(255, 255, 255, 255))?,
+            }
+            let x = (width - text.width() as f32)/2.0;
+            graphics::draw(ctx, &text, pt(x, y), 0.0)?;
+            y += text.height() as f32 + 10.0;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+use simulation::*;
+use map_loading::LoadingMap;
+use std::fs::File;
+use std::io::{Read, BufWriter};
+use bincode::*;
+
+//bump whenever the format or the simulation rules change, old replays won't play back the same
+pub const REPLAY_VERSION: u32 = 10;
+
+//Everything needed to re-run a game: the sim is deterministic, so the map and the commands are enough
+#[derive(Serialize, Deserialize)]
+pub struct Replay{
+    pub version: u32,
+    pub level: LoadingMap,
+    pub tick_time: u32,
+    pub player: Player, //who recorded it
+    pub turns: Vec<Vec<AchronalCommand>>,
+}
+
+impl Replay{
+    pub fn new(level: LoadingMap, tick_time: u32, player: Player) -> Replay{
+        Replay{version: REPLAY_VERSION, level, tick_time, player, turns: Vec::new()}
+    }
+    pub fn record(&mut self, commands: &Vec<AchronalCommand>){
+        self.turns.push(commands.clone());
+    }
+    pub fn save(&self, path: &str) -> Result<()>{
+        let file = File::create(path)?;
+        serialize_into(BufWriter::new(file), self)
+    }
+    pub fn load(path: &str) -> Result<Replay>{
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        //the version comes first, and the rest may not even read as a replay if it's different
+        let version: u32 = deserialize(&data)?;
+        if version != REPLAY_VERSION{
+            return Err(Box::new(ErrorKind::Custom(format!("Replay is version {}, expected {}", version, REPLAY_VERSION))));
+        }
+        deserialize(&data)
+    }
+}
+
+//Feeds a recorded game back in, one turn at a time
+pub struct Playback{
+    pub replay: Replay,
+    pub next_turn: usize,
+    pub paused: bool,
+    pub speed: u32, //turns per tick_time
+}
+pub const MAX_PLAYBACK_SPEED: u32 = 16;
+
+impl Playback{
+    pub fn new(replay: Replay) -> Playback{
+        Playback{replay, next_turn: 0, paused: false, speed: 1}
+    }
+    pub fn finished(&self) -> bool{
+        self.next_turn >= self.replay.turns.len()
+    }
+    pub fn next_commands(&mut self) -> Option<Vec<AchronalCommand>>{
+        if self.paused || self.finished(){
+            None
+        } else {
+            self.next_turn += 1;
+            Some(self.replay.turns[self.next_turn-1].clone())
+        }
+    }
+    pub fn faster(&mut self){
+        if self.speed < MAX_PLAYBACK_SPEED{
+            self.speed *= 2;
+        }
+    }
+    pub fn slower(&mut self){
+        if self.speed > 1{
+            self.speed /= 2;
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use simulation::*;
+use timeline::*;
+use replay::*;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use bincode::*;
+
+//bump whenever the format or anything in the timeline changes, old saves won't load
+pub const SAVE_VERSION: u32 = 11;
+
+//A game in progress, to carry on later. Offline only: a network game also needs everyone else's.
+//The recording comes along so the replay still covers the whole game, and has the level.
+#[derive(Deserialize)]
+pub struct SaveGame{
+    pub timeline: Timeline,
+    pub orders: CommandBuffer, //orders given that haven't happened yet
+    pub turn: u64,
+    pub player: Player,
+    pub recording: Replay,
+}
+//the same layout as SaveGame, without copying everything to save it
+#[derive(Serialize)]
+struct SaveGameRef<'a>{
+    timeline: &'a Timeline,
+    orders: &'a CommandBuffer,
+    turn: u64,
+    player: Player,
+    recording: &'a Replay,
+}
+
+pub fn save_game(path: &str, timeline: &Timeline, orders: &CommandBuffer, turn: u64, player: Player, recording: &Replay) -> Result<()>{
+    let mut writer = BufWriter::new(File::create(path)?);
+    serialize_into(&mut writer, &SAVE_VERSION)?;
+    serialize_into(&mut writer, &SaveGameRef{timeline, orders, turn, player, recording})
+}
+pub fn load_game(path: &str) -> Result<SaveGame>{
+    let mut reader = BufReader::new(File::open(path)?);
+    let version: u32 = deserialize_from(&mut reader)?;
+    if version != SAVE_VERSION{
+        return Err(Box::new(ErrorKind::Custom(format!("Save is version {}, expected {}", version, SAVE_VERSION))));
+    }
+    deserialize_from(&mut reader)
+}
@@ -0,0 +1,102 @@
+#![allow(dead_code)]
+//Dedicated server: hosts the lobby, then relays commands between the players, keeping the turn clock and judging the game.
+//usage: server <level.toml> [conf.toml]
+extern crate chronox;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+extern crate toml;
+#[macro_use]
+extern crate serde_derive;
+
+use chronox::map_loading;
+use chronox::simulation::*;
+use chronox::timeline::*;
+use chronox::victory::*;
+use chronox::networking::*;
+use chronox::lobby::*;
+use chronox::relay::*;
+
+//how long to keep relaying after the game is decided, so everyone gets the last turns
+const LINGER_SECS: u64 = 5;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Config{
+    system: SystemConfig,
+    timeline: Option<TimelineConfig>,
+}
+
+pub fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2{
+        eprintln!("usage: server <level.toml> [conf.toml]");
+        return;
+    }
+    let level_data = fs::read(&args[1]).expect("Couldn't read level file");
+    let mut level: map_loading::LoadingMap = toml::from_slice(&level_data).expect("Couldn't parse level file");
+    let (system, timeline_config) = match args.get(2){
+        Some(path) => {
+            let conf_data = fs::read(path).expect("Couldn't read config file");
+            let conf: Config = toml::from_slice(&conf_data).expect("Couldn't parse config file");
+            (conf.system, conf.timeline)
+        }
+        None => (SystemConfig{tick_time: 100, command_delay: 4, port_from: Some(40004), port_to: Some(40004), replay_file: None, save_file: None, level: None, teams: None}, None),
+    };
+    //clients look for the level in their resources folder
+    let level_name = format!("/{}", Path::new(&args[1]).file_name().unwrap().to_string_lossy());
+    let settings = GameSettings{level: level_name, map_hash: map_hash(&level_data), tick_time: system.tick_time,
+        command_delay: system.command_delay, teams: system.teams.clone(), timeline: timeline_config.clone()};
+    if let Some(teams) = system.teams.clone(){
+        map_loading::set_teams(&mut level, teams);
+    }
+    if let Some(config) = timeline_config{
+        map_loading::set_timeline(&mut level, config);
+    }
+    let conditions = map_loading::load_victory(&level);
+    let teams = map_loading::load_teams(&level).expect("Bad teams");
+    let config = map_loading::load_timeline(&level).expect("Bad timeline rules");
+    let combat = map_loading::load_combat(&level).expect("Bad combat rules");
+    let mut sim = Simulation::with_teams(map_loading::load_map(level).expect("Bad level"), teams);
+    sim.combat = combat;
+    let players = starting_players(&sim);
+    let timeline = Timeline::new(sim, conditions, config);
+
+    let port = system.port_from.unwrap_or(40004);
+    let mut lobby = LobbyHost::new(settings, &players, port, Player::PASSIVE).unwrap_or_else(|e| panic!("Couldn't host on port {}: {}", port, e));
+    let mut status = Vec::new();
+    loop {
+        let event = lobby.update();
+        if lobby.status() != status{
+            status = lobby.status();
+            println!("{}", status.join(" | "));
+        }
+        if let LobbyEvent::Start = event{
+            break;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    let mut relay = Relay::new(lobby.finish(), timeline);
+    println!("Game started");
+    let mut decided_at = None;
+    loop {
+        if let Some(d) = relay.update(){
+            println!("DESYNC: {:?} disagrees with the server on the world at time {} (server {:016x}, theirs {:016x})", d.peer, d.time, d.local_hash, d.remote_hash);
+        }
+        if decided_at.is_none(){
+            if let Some(result) = relay.timeline.result(){
+                println!("Match decided at time {} by {:?}: winners {:?}", result.time, result.condition, result.winners);
+                decided_at = Some(Instant::now());
+            }
+        }
+        if decided_at.map_or(false, |t| Instant::now() - t > Duration::from_secs(LINGER_SECS)){
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    println!("{} commands came in too late", relay.late);
+}
@@ -1,4 +1,7 @@
 use library::*;
+use combat::{self, CombatConfig};
+use std::hash::Hasher;
+use std::cmp::min;
 //use ggez::nalgebra as na;
 
 pub use orders::*;
@@ -9,34 +12,64 @@ pub type EdgeInd = EdgeIndex<u16>;
 pub use plain_enum::*;
 plain_enum_mod!(player_enum, derive(FromPrimitive, ToPrimitive, Serialize, Deserialize,), map_derive(Serialize, Deserialize, Copy, ), Player {
     PASSIVE,
-    P1, P2, /*P3, P4,*/
+    P1, P2, P3, P4,
 });
 
 pub type PlayerArr<T> = EnumMap<Player, T>;
+//allies share a team. PASSIVE is always alone on team 0.
+pub type Team = u8;
+pub fn default_teams() -> PlayerArr<Team>{
+    Player::map_from_fn(|p| p as Team)
+}
 
 pub const ARMY_SPEED: i32 = 100;
+//planets that don't say otherwise make a unit every SPAWN_NEEDED/PRODUCTION ticks
 pub const SPAWN_NEEDED: u32 = 64;
+pub const PRODUCTION: u32 = 10;
+//what the special planet kinds do, in percent of normal
+pub const SHIPYARD_PRODUCTION: u32 = 200;
+pub const FORTRESS_DEFENSE: u32 = 200;
+pub const RELAY_SPEED: i32 = 150;
+//chrono energy per tick for each beacon owned in the present
+pub const BEACON_ENERGY: u32 = 1;
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum PlanetKind{
+    Normal,
+    Shipyard, //produces faster
+    Fortress, //whoever holds it fights harder there
+    Relay, //armies sent from it move faster
+    ChronoBeacon, //its owner gets more chrono energy
+}
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SimMetadata{
     pub total_living: PlayerArr<u32>,
     pub total_died: PlayerArr<u32>,
+    pub planets_owned: PlayerArr<u32>,
+    pub beacons_owned: PlayerArr<u32>,
 }
 impl SimMetadata{
     pub fn new() -> SimMetadata{
-        SimMetadata{total_living: PlayerArr::new(0), total_died: PlayerArr::new(0)}
+        SimMetadata{total_living: PlayerArr::new(0), total_died: PlayerArr::new(0), planets_owned: PlayerArr::new(0), beacons_owned: PlayerArr::new(0)}
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
 pub struct Planet {
     pub loc: Ipt,
     pub count: PlayerArr<u32>,
     pub fight_progess: PlayerArr<u32>,
+    pub battle_ticks: u32, //how long the current battle has gone on
     pub send_all: PlayerArr<Option<NodeInd>>,
     pub owner: Player,
     pub owner_strength: u32,
-    pub max_strength: u32,
+    pub max_strength: u32, //how long capturing it takes
     pub spawn_progress: u32,
+    pub production: u32, //spawn progress per tick
+    pub spawn_needed: u32, //progress per unit
+    pub defense: u32, //percent of normal fighting strength for the owner's team
+    pub kind: PlanetKind,
 }
 impl Planet{
     pub fn new(loc: Ipt, owner: Player) -> Planet{
@@ -46,19 +79,24 @@ impl Planet{
             loc,
             count,
             fight_progess: PlayerArr::new(0),
+            battle_ticks: 0,
             send_all: PlayerArr::new(None),
             owner,
             owner_strength: 64,
             max_strength: 64,
             spawn_progress: 0,
+            production: PRODUCTION,
+            spawn_needed: SPAWN_NEEDED,
+            defense: 100,
+            kind: PlanetKind::Normal,
         }
     }
-    fn advance(&mut self, total_living: &mut PlayerArr<u32>, total_dead: &mut PlayerArr<u32>){
+    fn advance(&mut self, teams: &PlayerArr<Team>, combat: &CombatConfig, total_living: &mut PlayerArr<u32>, total_dead: &mut PlayerArr<u32>){
         //if owned, spawn more
         if self.owner != Player::PASSIVE {
-            self.spawn_progress += 10;
-            if self.spawn_progress >= SPAWN_NEEDED{
-                self.spawn_progress -= SPAWN_NEEDED;
+            self.spawn_progress += self.production_rate();
+            while self.spawn_progress >= self.spawn_needed{
+                self.spawn_progress -= self.spawn_needed;
                 self.count[self.owner] += 1;
             }
         } else {
@@ -69,21 +107,23 @@ impl Planet{
         for side in sides_found.clone(){
             total_living[side] += self.count[side];
         }
-        let sides_count = sides_found.len();
-        if sides_count < 2{//zero out all fighting progress if no battle
+        let teams_found = find_teams(&sides_found, teams);
+        let teams_count = teams_found.len();
+        if teams_count < 2{//zero out all fighting progress if no battle
             self.fight_progess = PlayerArr::new(0);
-            if sides_count == 1{//and advance ownership of the winner
-                if self.count[self.owner] == 0 {//owner has lost
+            self.battle_ticks = 0;
+            if teams_count == 1{//and advance ownership of the winner
+                if teams_found[0] != teams[self.owner] {//owner has lost
                     self.owner_strength -= 1;
                     if self.owner_strength <= 0{
                         if self.owner == Player::PASSIVE{
-                            self.owner = sides_found[0];
+                            self.owner = self.capturer(&sides_found);
                         } else {
                             self.owner = Player::PASSIVE;
                             self.owner_strength = self.max_strength;
                         }
                     }
-                } else {//owner has won
+                } else {//owner (or their allies) has won
                     if self.owner_strength <= self.max_strength {
                         self.owner_strength += 1
                     }
@@ -91,56 +131,156 @@ impl Planet{
             }
         } else {
             //otherwise, do fighting
-            for p_ref in &sides_found{
-                let p = *p_ref;
-                self.fight_progess[p] += self.count[p];//TODO: modify algorithm?
-                let kills = self.fight_progess[p]/100;
-                if kills > 0 {
-                    self.fight_progess[p] -= 100*kills;
-                    for p2_ref in &sides_found {
-                        let p2 = *p2_ref;
-                        if p2 != p {
-                            if self.count[p2] > kills {
-                                total_dead[p2] += kills;
-                                self.count[p2] -= kills;
-                            } else {
-                                total_dead[p2] += self.count[p2];
-                                self.count[p2] = 0;
-                            }
-                        }
-                    }
+            combat::fight(self, teams, combat, total_dead);
+        }
+    }
+    //spawn progress per tick, counting what its kind adds
+    pub fn production_rate(&self) -> u32{
+        match self.kind {
+            PlanetKind::Shipyard => self.production*SHIPYARD_PRODUCTION/100,
+            _ => self.production,
+        }
+    }
+    //percent of normal fighting strength for the owner's team, counting what its kind adds
+    pub fn defense_rate(&self) -> u32{
+        match self.kind {
+            PlanetKind::Fortress => self.defense*FORTRESS_DEFENSE/100,
+            _ => self.defense,
+        }
+    }
+    //when a team takes a neutral planet together, whoever has the most units there gets it (lowest player on a tie)
+    fn capturer(&self, sides_found: &Vec<Player>) -> Player{
+        let mut best = sides_found[0];
+        for &p in sides_found{
+            if self.count[p] > self.count[best]{
+                best = p;
+            }
+        }
+        best
+    }
+    //kills land on the biggest stack of the team first
+    pub fn kill_from_team(&mut self, team: Team, kills: u32, teams: &PlayerArr<Team>, total_dead: &mut PlayerArr<u32>){
+        for _ in 0..kills{
+            let mut target = None;
+            for p in Player::values(){
+                if teams[p] == team && self.count[p] > 0 && target.map_or(true, |t| self.count[p] > self.count[t]){
+                    target = Some(p);
                 }
             }
+            match target{
+                Some(t) => {
+                    self.count[t] -= 1;
+                    total_dead[t] += 1;
+                }
+                None => return,
+            }
         }
     }
 }
-#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
 pub struct ArmyGroup{
     pub direction: DIR,
     pub progress: i32,
+    pub speed: i32, //percent of the lane's speed
     pub count: u32,
     pub player: Player
 }
+//where armies met and fought on a lane, kept for a few ticks so it can be shown
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct Clash{
+    pub at: i32, //distance from the lane's source
+    pub time: ChronalTime,
+    pub dead: u32,
+}
+pub const CLASH_SHOW: ChronalTime = 10;
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct HyperLane{
     pub length: i32,
-    pub transfers: Vec<ArmyGroup>
+    pub speed: i32, //percent of ARMY_SPEED that armies move at here
+    pub one_way: Option<DIR>, //the only direction armies can be sent, if they can't go both ways
+    pub capacity: Option<u32>, //most units that can be on it at once
+    pub transfers: Vec<ArmyGroup>,
+    pub clashes: Vec<Clash>, //from the last CLASH_SHOW ticks
 }
-#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
 pub enum DIR{FORWARD, BACKWARD}
 
 impl HyperLane{
-    pub fn new() -> HyperLane{
-        HyperLane{transfers: Vec::new(), length: 5000}
+    pub fn new(length: i32) -> HyperLane{
+        HyperLane{transfers: Vec::new(), clashes: Vec::new(), length, speed: 100, one_way: None, capacity: None}
+    }
+    //how far armies move each tick
+    pub fn step(&self) -> i32{
+        ARMY_SPEED*self.speed/100
+    }
+    pub fn allows(&self, dir: DIR) -> bool{
+        self.one_way.map_or(true, |only| only == dir)
+    }
+    //units that can still be sent onto it
+    pub fn room(&self) -> u32{
+        match self.capacity {
+            Some(capacity) => capacity.saturating_sub(self.transfers.iter().map(|g| g.count).sum()),
+            None => u32::max_value(),
+        }
     }
 }
 
 pub type WorldGraph =  Graph<Planet, HyperLane, Undirected, u16>;
+
+//what one player can see of a world, indexed by node and edge index
+#[derive(Clone, Debug)]
+pub struct Visibility{
+    pub planets: Vec<bool>,
+    pub lanes: Vec<bool>,
+}
+impl Visibility{
+    pub fn planet(&self, node: NodeInd) -> bool{
+        self.planets[node.index()]
+    }
+    pub fn lane(&self, edge: EdgeInd) -> bool{
+        self.lanes[edge.index()]
+    }
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Simulation{
     pub world:WorldGraph,
     pub timestep: ChronalTime,
+    pub teams: PlayerArr<Team>,
+    pub combat: CombatConfig,
 }
 
+//what changed in a world from the tick before it. The map's layout never changes, so it's just planets and lane armies.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WorldDelta{
+    planets: Vec<(u16, Planet)>,
+    lanes: Vec<(u16, Vec<ArmyGroup>, Vec<Clash>)>,
+}
+//how a planet differs between two versions of the same tick, after history was rewritten
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PlanetChange{
+    pub node: NodeInd,
+    pub owner: Option<(Player, Player)>, //before and after, if it changed hands
+    pub count: PlayerArr<i32>, //units each player gained or lost there
+    pub battle: Option<bool>, //true if a battle appeared, false if one vanished
+}
+impl PlanetChange{
+    //whether it matters to this player's team
+    pub fn involves(&self, player: Player, sim: &Simulation) -> bool{
+        let owner = self.owner.map_or(false, |(before, after)| sim.allied(before, player) || sim.allied(after, player));
+        owner || Player::values().any(|p| self.count[p] != 0 && sim.allied(p, player))
+    }
+}
+
+pub fn find_teams(sides: &Vec<Player>, teams: &PlayerArr<Team>) -> Vec<Team>{
+    let mut teams_found = Vec::new();
+    for p in sides{
+        if !teams_found.contains(&teams[*p]){
+            teams_found.push(teams[*p]);
+        }
+    }
+    teams_found
+}
 
 pub fn find_sides_node(node: &Planet) -> Vec<Player>{
     let mut sides_found = Vec::new();
@@ -156,14 +296,18 @@ fn send_out(world: &mut WorldGraph, from: NodeInd, to: NodeInd, player:Player, p
     let edge_data = world.find_edge_undirected(from, to);
     if let Some((edge_ind, dir)) = edge_data {
         let (edge, node) = world.index_twice_mut(edge_ind, from);
-        let transfer_amount = (node.count[player] * (percent as u32)) / 100;
+        let order_dir = match dir {
+            Direction::Outgoing => DIR::FORWARD,
+            Direction::Incoming => DIR::BACKWARD
+        };
+        if !edge.allows(order_dir) {
+            return;
+        }
+        let transfer_amount = min((node.count[player] * (percent as u32)) / 100, edge.room());
         if transfer_amount > 0 {
             node.count[player] -= transfer_amount;
-            let order_dir = match dir {
-                Direction::Outgoing => DIR::FORWARD,
-                Direction::Incoming => DIR::BACKWARD
-            };
-            let new_follow = ArmyGroup { direction: order_dir, progress: 0, count: transfer_amount, player };
+            let speed = if node.kind == PlanetKind::Relay {RELAY_SPEED} else {100};
+            let new_follow = ArmyGroup { direction: order_dir, progress: 0, speed, count: transfer_amount, player };
             edge.transfers.push(new_follow);
         }
     } else {
@@ -173,7 +317,174 @@ fn send_out(world: &mut WorldGraph, from: NodeInd, to: NodeInd, player:Player, p
 
 impl Simulation{
     pub fn new(world: WorldGraph) -> Simulation{
-        Simulation{world, timestep: 0}
+        Simulation{world, timestep: 0, teams: default_teams(), combat: CombatConfig::default()}
+    }
+    pub fn with_teams(world: WorldGraph, teams: PlayerArr<Team>) -> Simulation{
+        Simulation{world, timestep: 0, teams, combat: CombatConfig::default()}
+    }
+    //whether armies can be sent from one planet to the other
+    pub fn can_send(&self, from: NodeInd, to: NodeInd) -> bool{
+        match self.world.find_edge_undirected(from, to) {
+            Some((edge_ind, Direction::Outgoing)) => self.world[edge_ind].allows(DIR::FORWARD),
+            Some((edge_ind, Direction::Incoming)) => self.world[edge_ind].allows(DIR::BACKWARD),
+            None => false,
+        }
+    }
+    pub fn allied(&self, a: Player, b: Player) -> bool{
+        self.teams[a] == self.teams[b]
+    }
+
+    //metadata for a world that wasn't produced by update (such as the starting map)
+    pub fn census(&self) -> SimMetadata{
+        let mut metadata = SimMetadata::new();
+        for node_ind in self.world.node_indices() {
+            let node = &self.world[node_ind];
+            for p in Player::values() {
+                metadata.total_living[p] += node.count[p];
+            }
+            metadata.planets_owned[node.owner] += 1;
+            if node.kind == PlanetKind::ChronoBeacon {
+                metadata.beacons_owned[node.owner] += 1;
+            }
+        }
+        for edge in self.world.edge_references() {
+            for group in &edge.weight().transfers {
+                metadata.total_living[group.player] += group.count;
+            }
+        }
+        metadata
+    }
+
+    //deterministic hash of the whole world, for checking that peers agree on it
+    pub fn state_hash(&self) -> u64{
+        let mut h = Fnv64::new();
+        h.write_u32(self.timestep);
+        for p in Player::values(){
+            h.write_u8(self.teams[p]);
+        }
+        for node_ind in self.world.node_indices(){
+            let node = &self.world[node_ind];
+            h.write_i32(node.loc.x);
+            h.write_i32(node.loc.y);
+            h.write_usize(node.owner.to_usize());
+            h.write_u32(node.owner_strength);
+            h.write_u32(node.max_strength);
+            h.write_u32(node.spawn_progress);
+            h.write_u32(node.battle_ticks);
+            h.write_u32(node.production);
+            h.write_u32(node.spawn_needed);
+            h.write_u32(node.defense);
+            h.write_u8(node.kind as u8);
+            for p in Player::values(){
+                h.write_u32(node.count[p]);
+                h.write_u32(node.fight_progess[p]);
+                h.write_usize(node.send_all[p].map(|t| t.index()+1).unwrap_or(0));
+            }
+        }
+        for edge_ref in self.world.edge_references(){
+            h.write_usize(edge_ref.source().index());
+            h.write_usize(edge_ref.target().index());
+            let edge = edge_ref.weight();
+            h.write_i32(edge.length);
+            h.write_i32(edge.speed);
+            h.write_u8(match edge.one_way {None => 0, Some(DIR::FORWARD) => 1, Some(DIR::BACKWARD) => 2});
+            h.write_u64(edge.capacity.map(|c| c as u64+1).unwrap_or(0));
+            for clash in &edge.clashes{
+                h.write_i32(clash.at);
+                h.write_u32(clash.time);
+                h.write_u32(clash.dead);
+            }
+            for group in &edge.transfers{
+                h.write_u8(match group.direction {DIR::FORWARD => 0, DIR::BACKWARD => 1});
+                h.write_i32(group.progress);
+                h.write_i32(group.speed);
+                h.write_u32(group.count);
+                h.write_usize(group.player.to_usize());
+            }
+        }
+        h.write(self.combat.resolver.name().as_bytes());
+        h.write_u32(self.combat.defender_advantage);
+        h.write_u32(self.combat.min_duration);
+        h.write_u8(self.combat.lane_battles as u8);
+        h.write_u8(self.combat.merge_armies as u8);
+        h.finish()
+    }
+
+    //a player sees planets their team owns or has units on, the planets next to those, and lanes their team has armies on.
+    //PASSIVE sees everything.
+    pub fn visibility(&self, player: Player) -> Visibility{
+        let everything = player == Player::PASSIVE;
+        let team = self.teams[player];
+        let ours = |p: Player| p != Player::PASSIVE && self.teams[p] == team;
+        let mut planets = vec![everything; self.world.node_count()];
+        let mut lanes = vec![everything; self.world.edge_count()];
+        if !everything{
+            for node_ind in self.world.node_indices(){
+                let node = &self.world[node_ind];
+                if ours(node.owner) || find_sides_node(node).into_iter().any(|p| ours(p)){
+                    planets[node_ind.index()] = true;
+                    for next in self.world.neighbors(node_ind){
+                        planets[next.index()] = true;
+                    }
+                }
+            }
+            for edge_ref in self.world.edge_references(){
+                if edge_ref.weight().transfers.iter().any(|g| ours(g.player)){
+                    lanes[edge_ref.id().index()] = true;
+                }
+            }
+        }
+        Visibility{planets, lanes}
+    }
+
+    //the changes from prev, which must be the tick before this
+    pub fn delta_from(&self, prev: &Simulation) -> WorldDelta{
+        debug_assert_eq!(prev.timestep+1, self.timestep);
+        let mut planets = Vec::new();
+        for node_ind in self.world.node_indices(){
+            if self.world[node_ind] != prev.world[node_ind]{
+                planets.push((node_ind.index() as u16, self.world[node_ind]));
+            }
+        }
+        let mut lanes = Vec::new();
+        for edge_ind in self.world.edge_indices(){
+            let (lane, prev_lane) = (&self.world[edge_ind], &prev.world[edge_ind]);
+            if lane.transfers != prev_lane.transfers || lane.clashes != prev_lane.clashes{
+                lanes.push((edge_ind.index() as u16, lane.transfers.clone(), lane.clashes.clone()));
+            }
+        }
+        WorldDelta{planets, lanes}
+    }
+    //what's different from an older version of the same tick
+    pub fn changes_from(&self, old: &Simulation) -> Vec<PlanetChange>{
+        debug_assert_eq!(old.timestep, self.timestep);
+        let battle = |planet: &Planet| find_teams(&find_sides_node(planet), &self.teams).len() > 1;
+        let mut changes = Vec::new();
+        for node in self.world.node_indices(){
+            let (before, after) = (&old.world[node], &self.world[node]);
+            if before == after {
+                continue;
+            }
+            let owner = if before.owner != after.owner {Some((before.owner, after.owner))} else {None};
+            let count = Player::map_from_fn(|p| after.count[p] as i32 - before.count[p] as i32);
+            let battle = if battle(before) != battle(after) {Some(battle(after))} else {None};
+            if owner.is_some() || battle.is_some() || count.iter().any(|&c| c != 0) {
+                changes.push(PlanetChange{node, owner, count, battle});
+            }
+        }
+        changes
+    }
+    //turn this into the next tick's world
+    pub fn apply_delta(&mut self, delta: &WorldDelta){
+        for &(node, planet) in &delta.planets{
+            self.world[NodeInd::new(node as usize)] = planet;
+        }
+        for &(edge, ref transfers, ref clashes) in &delta.lanes{
+            let lane = &mut self.world[EdgeInd::new(edge as usize)];
+            lane.transfers = transfers.clone();
+            lane.clashes = clashes.clone();
+        }
+        self.timestep += 1;
     }
 
     pub fn find_sides(&self, node: NodeInd) -> Vec<Player>{
@@ -186,12 +497,21 @@ impl Simulation{
         let mut total_planet = PlayerArr::new(0);
         let mut total_transit = PlayerArr::new(0);
         let mut total_died = PlayerArr::new(0);
+        let mut planets_owned = PlayerArr::new(0);
+        let mut beacons_owned = PlayerArr::new(0);
+        let mut lane_died = PlayerArr::new(0);
         let mut new_world: WorldGraph;
+        let teams = &self.teams;
+        let combat = &self.combat;
         {//metadata borrow scope
             new_world = self.world.map(
                 |_node_ind, node| {
                     let mut new_node = node.clone();
-                    new_node.advance(&mut total_planet, &mut total_died);
+                    new_node.advance(teams, combat, &mut total_planet, &mut total_died);
+                    planets_owned[new_node.owner] += 1;
+                    if new_node.kind == PlanetKind::ChronoBeacon {
+                        beacons_owned[new_node.owner] += 1;
+                    }
                     new_node
                 },
                 |edge_ind, edge| {
@@ -199,9 +519,10 @@ impl Simulation{
                     let mut new_vec = Vec::new();
                     let (s_ind, t_ind) = self.world.edge_endpoints(edge_ind).unwrap();
                     let edge_len = edge.length;
+                    let step = edge.step();
                     for group in &edge.transfers {
-                        total_transit[group.player] += group.count;
                         if group.progress > edge_len {
+                            total_transit[group.player] += group.count;
                             let ending = match group.direction {
                                 DIR::FORWARD => t_ind,
                                 DIR::BACKWARD => s_ind,
@@ -210,13 +531,26 @@ impl Simulation{
                         } else {
                             new_vec.push(ArmyGroup {
                                 direction: group.direction,
-                                progress: group.progress + ARMY_SPEED,
+                                progress: group.progress + step*group.speed/100,
+                                speed: group.speed,
                                 count: group.count,
                                 player: group.player
                             });
                         }
                     }
-                    HyperLane { length: edge_len, transfers: new_vec }
+                    let time = self.timestep+1;
+                    let mut clashes: Vec<Clash> = edge.clashes.iter().filter(|c| c.time + CLASH_SHOW > time).cloned().collect();
+                    if combat.merge_armies {
+                        combat::merge_groups(&mut new_vec, step);
+                    }
+                    if combat.lane_battles {
+                        combat::lane_fight(&mut new_vec, edge_len, teams, &*combat.resolver, time, &mut clashes, &mut lane_died);
+                    }
+                    //counted after the fighting, so the dead aren't also counted as living
+                    for group in &new_vec {
+                        total_transit[group.player] += group.count;
+                    }
+                    HyperLane { length: edge_len, speed: edge.speed, one_way: edge.one_way, capacity: edge.capacity, transfers: new_vec, clashes }
                 }
             );
             for removal in transfer_set {
@@ -254,9 +588,12 @@ impl Simulation{
                 }
             }
         }
+        for p in Player::values() {
+            total_died[p] += lane_died[p];
+        }
         let total_living = Player::map_from_fn(|player| total_planet[player] + total_transit[player]);
-        let metadata = SimMetadata{total_died, total_living};
-        (Simulation{world: new_world, timestep: self.timestep+1}, metadata)
+        let metadata = SimMetadata{total_died, total_living, planets_owned, beacons_owned};
+        (Simulation{world: new_world, timestep: self.timestep+1, teams: self.teams.clone(), combat: self.combat.clone()}, metadata)
     }
     pub fn check_planets(&self, pos: Ipt, max_dist: i32) -> Option<NodeInd>{
         let mut dist = i32::max_value();
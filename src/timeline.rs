@@ -1,19 +1,99 @@
 //use library::*;
 use simulation::*;
-use std::collections::{VecDeque, BTreeSet};
-use std::ops::Index;
-type ChronoEnergy = u16;
-pub const MAX_CHRONOENERGY: ChronoEnergy = 500;
+use victory::*;
+use std::collections::{VecDeque, BTreeSet, BTreeMap};
+use std::ops::{Index, Range};
+use std::cmp::{max, min};
+use std::mem;
+pub type ChronoEnergy = u16;
+//how many worlds to keep after they fall off the left edge, for desync checks and dumps
+pub const FINAL_STATE_HISTORY: usize = 200;
+//a whole world is kept this often, the rest are stored as changes from the tick before
+pub const KEYFRAME_INTERVAL: ChronalTime = 16;
+//how much acting further back in time costs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum CostCurve{
+    Linear, //one energy per tick back
+    Squared, //plus (ticks back)²/squared_ticks, so going far back gets expensive fast
+}
+//where chrono energy comes from, on top of energy_regen
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Income{
+    Flat,
+    Planets, //every planet owned in the present gives one energy every ticks_per_energy
+}
+//the rules of time, from the level's [timeline] section or the host's conf.toml. Anything left out is the default.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TimelineConfig{
+    pub history: ChronalTime, //ticks until the left edge starts moving
+    pub fill_speed: u8, //how fast the right edge moves until then, afterwards it moves at 1
+    pub wave_spacing: ChronalTime, //ticks between the timewaves that come from the left edge
+    pub wave_speed: u8,
+    pub starting_energy: ChronoEnergy,
+    pub max_energy: ChronoEnergy,
+    pub energy_regen: ChronoEnergy, //per tick
+    pub cost: CostCurve,
+    pub squared_ticks: ChronalTime,
+    pub income: Income,
+    pub ticks_per_energy: u32,
+    pub clear_refund: u8, //percent of what cleared orders cost that's given back
+    pub jump_cost: ChronoEnergy, //for a timejump, wherever it goes
+}
+impl Default for TimelineConfig{
+    fn default() -> TimelineConfig{
+        TimelineConfig{history: 1000, fill_speed: 2, wave_spacing: 40, wave_speed: 3, starting_energy: 450, max_energy: 500, energy_regen: 1,
+            cost: CostCurve::Linear, squared_ticks: 200, income: Income::Flat, ticks_per_energy: 20, clear_refund: 0, jump_cost: 0}
+    }
+}
+impl TimelineConfig{
+    //returns what's wrong, if anything
+    pub fn problem(&self) -> Option<&'static str>{
+        if self.history == 0 {
+            Some("history must be at least 1")
+        } else if self.fill_speed == 0 || self.wave_speed == 0 {
+            Some("fill_speed and wave_speed must be at least 1")
+        } else if self.wave_spacing < self.wave_speed as ChronalTime {
+            Some("wave_spacing must be at least wave_speed")
+        } else if self.max_energy == 0 {
+            Some("max_energy must be at least 1")
+        } else if self.starting_energy > self.max_energy {
+            Some("starting_energy can't be more than max_energy")
+        } else if self.squared_ticks == 0 || self.ticks_per_energy == 0 {
+            Some("squared_ticks and ticks_per_energy must be at least 1")
+        } else if self.clear_refund > 100 {
+            Some("clear_refund is a percent, at most 100")
+        } else {
+            None
+        }
+    }
+}
+#[derive(Serialize, Deserialize)]
+enum StoredWorld{
+    Keyframe(Simulation),
+    Delta(WorldDelta),
+}
+//what the last timewave to change a tick changed there
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Changes{
+    pub seen: ChronalTime, //the present when it happened
+    pub planets: Vec<PlanetChange>,
+}
+#[derive(Serialize, Deserialize)]
 struct TimePoint{
-    world: Simulation,
+    world: StoredWorld,
     commands: Vec<ChronalCommand>,
     metadata: SimMetadata,
+    changes: Option<Changes>,
+    paid: Vec<(Player, Option<NodeInd>, ChronoEnergy)>, //what each order here cost, for refunds when they're cleared
 }
-#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize, Copy, Clone)]
 pub struct Timewave{
     pub time: ChronalTime,
     pub speed: u8,
 }
+//everything about a game in progress, so it can be saved and picked up again
+#[derive(Serialize, Deserialize)]
 pub struct Timeline{
     multiverse: VecDeque<TimePoint>,
     pub left_edge: ChronalTime,
@@ -22,18 +102,47 @@ pub struct Timeline{
     pub timewaves: VecDeque<Timewave>,
     pub player_timewaves: PlayerArr<Timewave>,
     pub chrono_energy: PlayerArr<ChronoEnergy>,
+    income_progress: PlayerArr<u32>, //towards the next energy from planets
+    pub victory: VictoryTracker,
+    pub config: TimelineConfig,
+    final_states: VecDeque<StoredWorld>, //the worlds just before the left edge, stored the same way. The oldest is a keyframe.
     next_wave: i64,//current time of the next timewave to spawn (should be < left_edge)
+    views: BTreeMap<ChronalTime, Simulation>, //the world at each time a player's timewave is at
+    //worlds where timewaves stopped last tick, so they don't need rebuilding next time.
+    //Keyframes don't need it, and only one per keyframe interval is kept, by interval.
+    #[serde(skip)]
+    recent: BTreeMap<ChronalTime, Simulation>,
+}
+//rebuild a world from the keyframe before it. stored(i) is the i-th of a run of stored worlds.
+fn rebuild<'a, F: Fn(usize) -> &'a StoredWorld>(index: usize, stored: F) -> Simulation{
+    let mut start = index;
+    let mut world = loop {
+        match *stored(start) {
+            StoredWorld::Keyframe(ref world) => break world.clone(),
+            StoredWorld::Delta(_) => start -= 1,
+        }
+    };
+    for i in (start+1)..(index+1) {
+        if let StoredWorld::Delta(ref delta) = *stored(i) {
+            world.apply_delta(delta);
+        }
+    }
+    world
 }
 impl Timeline{
-    pub fn new(starting: Simulation) -> Timeline{
+    pub fn new(starting: Simulation, conditions: Vec<VictoryCondition>, config: TimelineConfig) -> Timeline{
         let mut multiverse = VecDeque::new();
-        let timepoint = TimePoint{commands: Vec::new(), world: starting, metadata: SimMetadata::new()};
+        let victory = VictoryTracker::new(conditions, starting_players(&starting), starting.teams.clone());
+        let metadata = starting.census();
+        let mut views = BTreeMap::new();
+        views.insert(0, starting.clone());
+        let timepoint = TimePoint{commands: Vec::new(), world: StoredWorld::Keyframe(starting), metadata, changes: None, paid: Vec::new()};
         multiverse.push_front(timepoint);
         let mut timewaves = VecDeque::new();
-        timewaves.push_front(Timewave{time:0, speed: 2});//initial right-edge timewave
+        timewaves.push_front(Timewave{time:0, speed: config.fill_speed});//initial right-edge timewave
         let player_timewaves = PlayerArr::new(Timewave{time: 0, speed: 1});
-        let chrono_energy = PlayerArr::new(450);
-        Timeline{multiverse, left_edge: 0, right_edge: 1, present: 0, timewaves, player_timewaves, chrono_energy, next_wave: -1}
+        let chrono_energy = PlayerArr::new(config.starting_energy);
+        Timeline{multiverse, left_edge: 0, right_edge: 1, present: 0, timewaves, player_timewaves, chrono_energy, income_progress: PlayerArr::new(0), victory, config, final_states: VecDeque::new(), next_wave: -1, views, recent: BTreeMap::new()}
     }
     fn exists(&self, time: ChronalTime) -> bool{
         let index = (time - self.left_edge) as usize;
@@ -45,6 +154,97 @@ impl Timeline{
     fn get_time_mut( & mut self, time: ChronalTime) -> & mut TimePoint {
         &mut self.multiverse[(time - self.left_edge) as usize]
     }
+    //the left edge is always a keyframe
+    fn oldest_world(&self) -> &Simulation{
+        match self.multiverse[0].world {
+            StoredWorld::Keyframe(ref world) => world,
+            StoredWorld::Delta(_) => panic!("Left edge isn't a keyframe"),
+        }
+    }
+    fn world_at(&self, time: ChronalTime) -> Simulation{
+        match self.recent.get(&(time/KEYFRAME_INTERVAL)) {
+            Some(world) if world.timestep == time => world.clone(),
+            _ => rebuild((time - self.left_edge) as usize, |i| &self.multiverse[i].world),
+        }
+    }
+    fn take_recent(&mut self, time: ChronalTime) -> Option<Simulation>{
+        if self.recent.get(&(time/KEYFRAME_INTERVAL)).map_or(false, |world| world.timestep == time) {
+            self.recent.remove(&(time/KEYFRAME_INTERVAL))
+        } else {
+            None
+        }
+    }
+    //store a newly calculated world. prev is the world the tick before it.
+    fn store_world(&mut self, mut prev: Simulation, new: &Simulation, metadata: SimMetadata){
+        let time = new.timestep;
+        self.take_recent(time);
+        let stored = if time % KEYFRAME_INTERVAL == 0 || time == self.left_edge {
+            StoredWorld::Keyframe(new.clone())
+        } else {
+            StoredWorld::Delta(new.delta_from(&prev))
+        };
+        if !self.exists(time) {
+            self.multiverse.push_back(TimePoint{commands: Vec::new(), world: stored, metadata, changes: None, paid: Vec::new()});
+            return;
+        }
+        let old = mem::replace(&mut self.get_time_mut(time).world, stored);
+        self.get_time_mut(time).metadata = metadata;
+        //rebuild the old version, to see what the rewrite changed
+        match old {
+            StoredWorld::Keyframe(world) => prev = world,
+            StoredWorld::Delta(ref delta) => prev.apply_delta(delta),
+        }
+        let planets = new.changes_from(&prev);
+        if planets.len() > 0 {
+            self.get_time_mut(time).changes = Some(Changes{seen: self.present, planets});
+        }
+        //the next world was stored as changes from the old one, so redo it from the new one
+        if self.exists(time+1) {
+            if let StoredWorld::Delta(ref next) = self.get_time(time+1).world {
+                prev.apply_delta(next);
+            } else {
+                return;
+            }
+            self.get_time_mut(time+1).world = StoredWorld::Delta(prev.delta_from(new));
+        }
+    }
+    //the worlds timewaves stopped at this tick, in time order. The later one in an interval saves more rebuilding.
+    fn keep_recent(&mut self, stopped: Vec<Simulation>){
+        self.recent.clear();
+        for world in stopped {
+            if world.timestep % KEYFRAME_INTERVAL != 0 {
+                self.recent.insert(world.timestep/KEYFRAME_INTERVAL, world);
+            }
+        }
+    }
+    //drop views nobody is at any more, and build any a timewave got to without calculating it (such as a timejump)
+    fn refresh_views(&mut self){
+        let times: BTreeSet<ChronalTime> = Player::values().map(|p| self.player_timewaves[p].time).collect();
+        let views = mem::replace(&mut self.views, BTreeMap::new());
+        self.views = views.into_iter().filter(|&(time, _)| times.contains(&time)).collect();
+        for time in times {
+            if !self.views.contains_key(&time) {
+                let world = self.world_at(time);
+                self.views.insert(time, world);
+            }
+        }
+    }
+    //finalized worlds are kept the same way as the rest, with a keyframe every KEYFRAME_INTERVAL
+    fn store_final(&mut self, world: Simulation){
+        let prev = if world.timestep % KEYFRAME_INTERVAL != 0 {self.final_state(world.timestep-1)} else {None};
+        let stored = match prev {
+            Some(prev) => StoredWorld::Delta(world.delta_from(&prev)),
+            None => StoredWorld::Keyframe(world),
+        };
+        self.final_states.push_back(stored);
+        if self.final_states.len() > FINAL_STATE_HISTORY {
+            self.final_states.pop_front();
+            //the oldest has to be a keyframe
+            while let Some(&StoredWorld::Delta(_)) = self.final_states.front() {
+                self.final_states.pop_front();
+            }
+        }
+    }
 
     pub fn get_metadata(&self, time: ChronalTime) -> &SimMetadata{
         if self.exists(time) {
@@ -53,19 +253,87 @@ impl Timeline{
             self.get_metadata(time - 1)
         }
     }
+    //the world at any time on the timeline, for looking at without jumping there. Nothing is changed or cached.
+    pub fn preview(&self, time: ChronalTime) -> Option<Simulation>{
+        if time >= self.left_edge && self.exists(time) {
+            Some(self.world_at(time))
+        } else {
+            None
+        }
+    }
+    //what history rewrites last changed at this time, if anything
+    pub fn changes(&self, time: ChronalTime) -> Option<&Changes>{
+        if time >= self.left_edge && self.exists(time) {
+            self.get_time(time).changes.as_ref()
+        } else {
+            None
+        }
+    }
+    //times of the worlds that can no longer change that are still kept
+    pub fn final_times(&self) -> Range<ChronalTime>{
+        (self.left_edge - self.final_states.len() as ChronalTime)..self.left_edge
+    }
+    pub fn final_state(&self, time: ChronalTime) -> Option<Simulation>{
+        let times = self.final_times();
+        if time >= times.start && time < times.end {
+            Some(rebuild((time - times.start) as usize, |i| &self.final_states[i]))
+        } else {
+            None
+        }
+    }
+    pub fn result(&self) -> Option<&MatchResult>{
+        self.victory.result.as_ref()
+    }
+    //how far the left and right edges move each tick
+    pub fn edge_speeds(&self, present: f32) -> (f32, f32){
+        if present < self.config.history as f32 {
+            (0.0, self.config.fill_speed as f32)
+        } else {
+            (1.0, 1.0)
+        }
+    }
+    fn cost_back(&self, back: ChronalTime) -> ChronoEnergy{
+        let cost = match self.config.cost {
+            CostCurve::Linear => back,
+            CostCurve::Squared => back.saturating_add(back.saturating_mul(back)/self.config.squared_ticks),
+        };
+        min(cost, ChronoEnergy::max_value() as ChronalTime) as ChronoEnergy
+    }
     pub fn chrono_cost(&self, time: ChronalTime) -> ChronoEnergy{
         if time < self.present {
-            (self.present - time) as ChronoEnergy
+            self.cost_back(self.present - time)
         } else {
             0
         }
     }
+    //the earliest time this much energy reaches
     pub fn chrono_energy_limit(&self, energy: ChronoEnergy) -> ChronalTime{
-        if self.present > (energy as ChronalTime) {
-            self.present - (energy as ChronalTime)
-        } else {
-            0
+        let mut back = min(energy as ChronalTime, self.present);
+        while back > 0 && self.cost_back(back) > energy {
+            back -= 1;
         }
+        self.present - back
+    }
+    //energy a player gets this tick
+    fn income(&mut self, player: Player) -> ChronoEnergy{
+        let beacons = self.get_metadata(self.present).beacons_owned[player]*BEACON_ENERGY;
+        let beacons = min(beacons, ChronoEnergy::max_value() as u32) as ChronoEnergy;
+        self.base_income(player).saturating_add(beacons)
+    }
+    fn base_income(&mut self, player: Player) -> ChronoEnergy{
+        match self.config.income {
+            Income::Flat => self.config.energy_regen,
+            Income::Planets => {
+                let ticks_per_energy = self.config.ticks_per_energy;
+                self.income_progress[player] += self.get_metadata(self.present).planets_owned[player];
+                let earned = self.income_progress[player]/ticks_per_energy;
+                self.income_progress[player] %= ticks_per_energy;
+                self.config.energy_regen.saturating_add(min(earned, ChronoEnergy::max_value() as u32) as ChronoEnergy)
+            }
+        }
+    }
+    fn add_energy(&mut self, player: Player, energy: ChronoEnergy){
+        self.chrono_energy[player] = min(self.chrono_energy[player].saturating_add(energy), self.config.max_energy);
     }
     //returns if successful. On failure, destroy this timewave (or reset its speed to 1 if player)
     //adds the times needed to the BTreeSet to be evaluated afterwards
@@ -81,59 +349,127 @@ impl Timeline{
         }
         return true;
     }
+    //commands come from the network, so anything that would break the simulation is thrown out.
+    //Every peer has the same timeline when evaluating, so they all throw out the same ones.
+    fn valid_command(&self, order: &AchronalCommand) -> bool{
+        let sim = self.oldest_world();
+        let world = &sim.world;
+        let planet = |node: NodeInd| node.index() < world.node_count();
+        let lane = |from: NodeInd, to: NodeInd| planet(from) && planet(to) && sim.can_send(from, to);
+        let in_range = |time: ChronalTime| time >= self.left_edge && time < self.left_edge+(self.multiverse.len() as ChronalTime);
+        match order.event{
+            AchronalCommandTypes::Chronal(ref data) => {
+                data.player == order.player && in_range(data.time) && match (data.target, &data.command){
+                    (Some(from), &ChronalCommandTypes::Transport(ref t)) => lane(from, t.to) && t.percent <= 100,
+                    (Some(from), &ChronalCommandTypes::SendAll(ref s)) => match s.to {
+                        Some(to) => lane(from, to),
+                        None => planet(from),
+                    },
+                    (None, _) => false,
+                }
+            }
+            AchronalCommandTypes::Timejump(time) => time < self.right_edge,
+            AchronalCommandTypes::ClearCommands(ref data) => in_range(data.time) && planet(data.target),
+            AchronalCommandTypes::CommandDelay(delay) => delay >= MIN_COMMAND_DELAY && delay <= MAX_COMMAND_DELAY,
+        }
+    }
     pub fn evaluate_timestep(&mut self, commands: Vec<AchronalCommand>){
         //regenerate chronoenergy
         for player in Player::values(){
-            if self.chrono_energy[player] < MAX_CHRONOENERGY{
-                self.chrono_energy[player] += 1;
-            }
+            let income = self.income(player);
+            self.add_energy(player, income);
         }
         //first evaluate events/orders that have been through the buffer
         for order in commands{
+            if !self.valid_command(&order){
+                println!("Ignoring invalid command {:?}", order);
+                continue;
+            }
             let player = order.player;
             match order.event{
                 AchronalCommandTypes::Chronal(data) => {
                     let cost = self.chrono_cost(data.time);
-                    if cost < self.chrono_energy[player] {
+                    if cost <= self.chrono_energy[player] {
                         self.chrono_energy[player] -= cost;
                         let timepoint = self.get_time_mut(data.time);
+                        timepoint.paid.push((player, data.target, cost));
                         timepoint.commands.push(data);
                     }
                 },
                 AchronalCommandTypes::Timejump(data) => {
-                    self.player_timewaves[player].time = data;
+                    let cost = self.config.jump_cost;
+                    if cost <= self.chrono_energy[player] {
+                        self.chrono_energy[player] -= cost;
+                        //the left edge may have passed it while the order was in the buffer
+                        self.player_timewaves[player].time = max(data, self.left_edge);
+                    }
                 },
                 AchronalCommandTypes::ClearCommands(data) => {
                     let cost = self.chrono_cost(data.time);
-                    if cost < self.chrono_energy[player] {
+                    if cost <= self.chrono_energy[player] {
                         self.chrono_energy[player] -= cost;
                         let left_edge = self.left_edge;
                         let from_time = (data.time - left_edge) as usize;
+                        let mut refund = 0;
                         for i in from_time..self.multiverse.len() {
                             let m = &mut self.multiverse[i];
                             m.commands.retain(|command_data: &ChronalCommand| {
                                 debug_assert_eq!((i as ChronalTime) + left_edge, command_data.time);
                                 (player != command_data.player || command_data.target != Some(data.target))
                             });
+                            m.paid.retain(|&(p, target, cost)| {
+                                let cleared = p == player && target == Some(data.target);
+                                if cleared {
+                                    refund += cost as u32;
+                                }
+                                !cleared
+                            });
                         }
+                        let refund = refund*(self.config.clear_refund as u32)/100;
+                        self.add_energy(player, min(refund, ChronoEnergy::max_value() as u32) as ChronoEnergy);
                     }
                 },
+                AchronalCommandTypes::CommandDelay(_) => {},//handled by whoever owns the command buffer
             }
         }
         //move timeline forward
-        if self.present < 1000 {
-            self.right_edge += 2;
+        if self.present < self.config.history {
+            self.right_edge += self.config.fill_speed as ChronalTime;
         } else {
             self.right_edge += 1;
             self.left_edge += 1;
-            self.multiverse.pop_front();
+            //this time can no longer change, so it is safe to judge the match on it
+            let finalized = self.multiverse.pop_front().unwrap();
+            let world = match finalized.world {
+                StoredWorld::Keyframe(world) => world,
+                StoredWorld::Delta(_) => panic!("Left edge isn't a keyframe"),
+            };
+            //so the new left edge has to become one
+            let next = match self.multiverse[0].world {
+                StoredWorld::Delta(ref delta) => {
+                    let mut next = world.clone();
+                    next.apply_delta(delta);
+                    Some(next)
+                }
+                StoredWorld::Keyframe(_) => None,
+            };
+            if let Some(next) = next {
+                self.multiverse[0].world = StoredWorld::Keyframe(next);
+            }
+            self.take_recent(world.timestep);
+            self.victory.check(world.timestep, &finalized.metadata);
+            self.store_final(world);
+            //a timejump to the old left edge would be left behind
+            for player in Player::values(){
+                self.player_timewaves[player].time = max(self.player_timewaves[player].time, self.left_edge);
+            }
         }
         self.present += 1;
 
-        self.next_wave += 3;
+        self.next_wave += self.config.wave_speed as i64;
         if (self.left_edge as i64) < self.next_wave{
-            self.next_wave -= 40;
-            self.timewaves.push_front(Timewave{time:self.left_edge, speed: 3});
+            self.next_wave -= self.config.wave_spacing as i64;
+            self.timewaves.push_front(Timewave{time:self.left_edge, speed: self.config.wave_speed});
         }
 
         //move timewaves forward
@@ -159,23 +495,32 @@ impl Timeline{
                 self.timewaves.remove(i);
             }
         }
-        //actually do the world updates
+        //actually do the world updates. Waves cover runs of times, so the last world made is usually the next one needed.
+        let mut last: Option<Simulation> = None;
+        let mut stopped = Vec::new();
         for prev_time in times_to_update {
             let time = prev_time+1;
-            let (new_world, metadata) = {
-                let prev = self.get_time(prev_time);
-                prev.world.update(&prev.commands)
+            let prev = match last.take() {
+                Some(world) if world.timestep == prev_time => world,
+                other => {
+                    stopped.extend(other);
+                    match self.take_recent(prev_time) {
+                        Some(world) => world,
+                        None => self.world_at(prev_time),
+                    }
+                }
             };
+            let (new_world, metadata) = prev.update(&self.get_time(prev_time).commands);
             assert_eq!(new_world.timestep, time);
-            if self.exists(time) {
-                let t = self.get_time_mut(time);
-                t.world = new_world;
-                t.metadata = metadata;
-            } else {
-                let timepoint = TimePoint { commands: Vec::new(), world: new_world, metadata };
-                self.multiverse.push_back(timepoint);
+            self.store_world(prev, &new_world, metadata);
+            if Player::values().any(|p| self.player_timewaves[p].time == time){
+                self.views.insert(time, new_world.clone());
             }
+            last = Some(new_world);
         }
+        stopped.extend(last);
+        self.keep_recent(stopped);
+        self.refresh_views();
     }
 }
 
@@ -184,6 +529,6 @@ impl Index<Player> for Timeline {
     type Output = Simulation;
 
     fn index(&self, player: Player) -> &Simulation {
-        &self.multiverse[(self.player_timewaves[player].time - self.left_edge) as usize].world
+        &self.views[&self.player_timewaves[player].time]
     }
 }
\ No newline at end of file
@@ -0,0 +1,343 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+//Reliable messages over UDP. Messages are split into fragments, every fragment is acked,
+//and only the fragments that haven't been acked get resent. Messages are delivered whole, but not in order.
+
+pub const MAX_PAYLOAD: usize = 1024; //bytes of message per fragment, keeps packets under a typical MTU
+pub const MAX_FRAGMENTS: usize = 64; //so a message is at most 64KB
+pub const MAX_MESSAGE: usize = MAX_PAYLOAD*MAX_FRAGMENTS;
+//message id (u32), fragment index (u16), fragment count (u16)
+pub const FRAGMENT_HEADER: usize = 8;
+const RESEND_MIN_MS: f32 = 50.0;
+const RESEND_MAX_MS: f32 = 1000.0;
+const ACKS_PER_PACKET: usize = 128;
+const MAX_PARTIAL: usize = 64; //messages being reassembled at once, anything more is dropped
+
+struct Sent{
+    data: Vec<u8>, //whole fragment, header included
+    last_sent: Instant,
+    resent: bool, //acks for resent fragments are ambiguous, so they aren't timed
+}
+struct Partial{
+    fragments: Vec<Option<Vec<u8>>>,
+    remaining: usize,
+}
+
+//one of these per peer
+pub struct Channel{
+    next_id: u32,
+    unacked: BTreeMap<(u32, u16), Sent>,
+    partial: BTreeMap<u32, Partial>,
+    delivered_below: u32, //every message id under this has been delivered
+    delivered: BTreeSet<u32>, //and so have these, which are above it
+    acks: Vec<(u32, u16)>, //waiting to be sent back
+    pub resent: u64,
+    pub rtt_ms: Option<f32>, //smoothed round trip time
+    pub jitter_ms: f32, //smoothed deviation of the round trip time
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16){
+    buf.push(v as u8);
+    buf.push((v >> 8) as u8);
+}
+fn put_u32(buf: &mut Vec<u8>, v: u32){
+    put_u16(buf, v as u16);
+    put_u16(buf, (v >> 16) as u16);
+}
+fn get_u16(buf: &[u8], at: usize) -> Option<u16>{
+    if buf.len() < at+2 {
+        return None;
+    }
+    Some((buf[at] as u16) | ((buf[at+1] as u16) << 8))
+}
+fn get_u32(buf: &[u8], at: usize) -> Option<u32>{
+    let low = get_u16(buf, at)? as u32;
+    let high = get_u16(buf, at+2)? as u32;
+    Some(low | (high << 16))
+}
+
+impl Channel{
+    pub fn new() -> Channel{
+        Channel{next_id: 0, unacked: BTreeMap::new(), partial: BTreeMap::new(), delivered_below: 0,
+            delivered: BTreeSet::new(), acks: Vec::new(), resent: 0, rtt_ms: None, jitter_ms: 0.0}
+    }
+    //split a message into fragments to send, keeping them until they're acked
+    pub fn send(&mut self, message: &[u8]) -> Vec<Vec<u8>>{
+        assert!(message.len() <= MAX_MESSAGE, "Message of {} bytes is too large to send", message.len());
+        let id = self.next_id;
+        self.next_id += 1;
+        let count = if message.len() == 0 {1} else {(message.len()+MAX_PAYLOAD-1)/MAX_PAYLOAD};
+        let now = Instant::now();
+        let mut fragments = Vec::new();
+        for i in 0..count{
+            let mut data = Vec::with_capacity(FRAGMENT_HEADER+MAX_PAYLOAD);
+            put_u32(&mut data, id);
+            put_u16(&mut data, i as u16);
+            put_u16(&mut data, count as u16);
+            let end = ::std::cmp::min((i+1)*MAX_PAYLOAD, message.len());
+            data.extend_from_slice(&message[i*MAX_PAYLOAD..end]);
+            self.unacked.insert((id, i as u16), Sent{data: data.clone(), last_sent: now, resent: false});
+            fragments.push(data);
+        }
+        fragments
+    }
+    //fragments that have gone too long without an ack
+    pub fn resend(&mut self) -> Vec<Vec<u8>>{
+        let now = Instant::now();
+        let timeout = Duration::from_millis(self.resend_after_ms() as u64);
+        let mut fragments = Vec::new();
+        for sent in self.unacked.values_mut(){
+            if now - sent.last_sent > timeout{
+                sent.last_sent = now;
+                sent.resent = true;
+                fragments.push(sent.data.clone());
+            }
+        }
+        self.resent += fragments.len() as u64;
+        fragments
+    }
+    //a bit over the worst round trip we'd expect
+    fn resend_after_ms(&self) -> f32{
+        match self.rtt_ms{
+            Some(rtt) => (rtt+4.0*self.jitter_ms).max(RESEND_MIN_MS).min(RESEND_MAX_MS),
+            None => 100.0,
+        }
+    }
+    //smoothed like TCP does it
+    fn time_ack(&mut self, sample: f32){
+        match self.rtt_ms{
+            Some(rtt) => {
+                self.jitter_ms = 0.75*self.jitter_ms + 0.25*(rtt-sample).abs();
+                self.rtt_ms = Some(0.875*rtt + 0.125*sample);
+            }
+            None => {
+                self.rtt_ms = Some(sample);
+                self.jitter_ms = sample/2.0;
+            }
+        }
+    }
+    pub fn pending(&self) -> usize{
+        self.unacked.len()
+    }
+    fn is_delivered(&self, id: u32) -> bool{
+        id < self.delivered_below || self.delivered.contains(&id)
+    }
+    //returns the whole message once its last fragment arrives
+    pub fn receive_fragment(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>, &'static str>{
+        let (id, index, count) = match (get_u32(data, 0), get_u16(data, 4), get_u16(data, 6)){
+            (Some(id), Some(index), Some(count)) => (id, index as usize, count as usize),
+            _ => return Err("fragment header cut off"),
+        };
+        let payload = &data[FRAGMENT_HEADER..];
+        if count == 0 || count > MAX_FRAGMENTS || index >= count || payload.len() > MAX_PAYLOAD{
+            return Err("bad fragment header");
+        }
+        if self.partial.get(&id).map_or(false, |partial| partial.fragments.len() != count){
+            return Err("fragment count changed");
+        }
+        //ack even if we already have it, the last ack may have been lost
+        self.acks.push((id, index as u16));
+        if self.is_delivered(id){
+            return Ok(None);
+        }
+        if !self.partial.contains_key(&id){
+            if self.partial.len() >= MAX_PARTIAL{
+                return Err("too many messages being reassembled");
+            }
+            self.partial.insert(id, Partial{fragments: vec![None; count], remaining: count});
+        }
+        let done = {
+            let partial = self.partial.get_mut(&id).unwrap();
+            if partial.fragments[index].is_none(){
+                partial.fragments[index] = Some(payload.to_vec());
+                partial.remaining -= 1;
+            }
+            partial.remaining == 0
+        };
+        if !done{
+            return Ok(None);
+        }
+        let partial = self.partial.remove(&id).unwrap();
+        let mut message = Vec::new();
+        for fragment in partial.fragments{
+            message.extend(fragment.unwrap());
+        }
+        self.delivered.insert(id);
+        while self.delivered.remove(&self.delivered_below){
+            self.delivered_below += 1;
+        }
+        Ok(Some(message))
+    }
+    //ack packets are a count, then that many (message id, fragment index)
+    pub fn take_acks(&mut self) -> Vec<Vec<u8>>{
+        let mut packets = Vec::new();
+        for chunk in self.acks.chunks(ACKS_PER_PACKET){
+            let mut data = Vec::new();
+            put_u16(&mut data, chunk.len() as u16);
+            for &(id, index) in chunk{
+                put_u32(&mut data, id);
+                put_u16(&mut data, index);
+            }
+            packets.push(data);
+        }
+        self.acks.clear();
+        packets
+    }
+    pub fn receive_acks(&mut self, data: &[u8]) -> Result<(), &'static str>{
+        let count = get_u16(data, 0).ok_or("ack count cut off")? as usize;
+        if count > ACKS_PER_PACKET || data.len() != 2+count*6{
+            return Err("bad ack length");
+        }
+        let now = Instant::now();
+        for i in 0..count{
+            let at = 2+i*6;
+            let id = get_u32(data, at).unwrap();
+            let index = get_u16(data, at+4).unwrap();
+            if let Some(sent) = self.unacked.remove(&(id, index)){
+                if !sent.resent{
+                    let elapsed = now - sent.last_sent;
+                    self.time_ack(elapsed.as_secs() as f32*1000.0 + elapsed.subsec_nanos() as f32/1_000_000.0);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(len: usize) -> Vec<u8>{
+        (0..len).map(|i| (i*7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn out_of_order_fragments_reassemble(){
+        let mut sender = Channel::new();
+        let mut receiver = Channel::new();
+        let sent = message(MAX_PAYLOAD*3+10);
+        let fragments = sender.send(&sent);
+        assert_eq!(fragments.len(), 4);
+        for &i in &[3, 1, 0] {
+            assert_eq!(receiver.receive_fragment(&fragments[i]), Ok(None));
+        }
+        assert_eq!(receiver.receive_fragment(&fragments[2]), Ok(Some(sent)));
+    }
+
+    #[test]
+    fn messages_can_arrive_out_of_order(){
+        let mut sender = Channel::new();
+        let mut receiver = Channel::new();
+        let first = sender.send(&message(5));
+        let second = sender.send(&message(6));
+        assert_eq!(receiver.receive_fragment(&second[0]), Ok(Some(message(6))));
+        assert_eq!(receiver.receive_fragment(&first[0]), Ok(Some(message(5))));
+    }
+
+    #[test]
+    fn duplicate_fragments_are_delivered_once(){
+        let mut sender = Channel::new();
+        let mut receiver = Channel::new();
+        let sent = message(MAX_PAYLOAD+1);
+        let fragments = sender.send(&sent);
+        assert_eq!(receiver.receive_fragment(&fragments[0]), Ok(None));
+        assert_eq!(receiver.receive_fragment(&fragments[0]), Ok(None));
+        assert_eq!(receiver.receive_fragment(&fragments[1]), Ok(Some(sent)));
+        //resent after the message was delivered
+        assert_eq!(receiver.receive_fragment(&fragments[1]), Ok(None));
+        assert_eq!(receiver.receive_fragment(&fragments[0]), Ok(None));
+        //every copy is still acked, in case the first acks were lost
+        let acks = receiver.take_acks();
+        assert_eq!(acks.len(), 1);
+        sender.receive_acks(&acks[0]).unwrap();
+        assert_eq!(sender.pending(), 0);
+    }
+
+    #[test]
+    fn empty_message(){
+        let mut sender = Channel::new();
+        let mut receiver = Channel::new();
+        let fragments = sender.send(&[]);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(receiver.receive_fragment(&fragments[0]), Ok(Some(Vec::new())));
+    }
+
+    #[test]
+    fn largest_message_fits(){
+        let mut sender = Channel::new();
+        let mut receiver = Channel::new();
+        let sent = message(MAX_MESSAGE);
+        let fragments = sender.send(&sent);
+        assert_eq!(fragments.len(), MAX_FRAGMENTS);
+        let mut delivered = None;
+        for fragment in fragments.iter().rev() {
+            delivered = receiver.receive_fragment(fragment).unwrap();
+        }
+        assert_eq!(delivered, Some(sent));
+    }
+
+    #[test]
+    #[should_panic]
+    fn oversize_message_is_not_sent(){
+        Channel::new().send(&message(MAX_MESSAGE+1));
+    }
+
+    #[test]
+    fn oversize_fragments_are_rejected(){
+        let mut receiver = Channel::new();
+        let mut fragment = Channel::new().send(&message(MAX_PAYLOAD)).remove(0);
+        fragment.push(0);
+        assert_eq!(receiver.receive_fragment(&fragment), Err("bad fragment header"));
+        let mut too_many = Vec::new();
+        put_u32(&mut too_many, 0);
+        put_u16(&mut too_many, 0);
+        put_u16(&mut too_many, (MAX_FRAGMENTS+1) as u16);
+        assert_eq!(receiver.receive_fragment(&too_many), Err("bad fragment header"));
+    }
+
+    #[test]
+    fn bad_fragment_headers_are_rejected(){
+        let mut receiver = Channel::new();
+        assert_eq!(receiver.receive_fragment(&[1, 2, 3]), Err("fragment header cut off"));
+        let header = |id: u32, index: u16, count: u16| {
+            let mut data = Vec::new();
+            put_u32(&mut data, id);
+            put_u16(&mut data, index);
+            put_u16(&mut data, count);
+            data
+        };
+        assert_eq!(receiver.receive_fragment(&header(0, 0, 0)), Err("bad fragment header"));
+        assert_eq!(receiver.receive_fragment(&header(0, 2, 2)), Err("bad fragment header"));
+        assert_eq!(receiver.receive_fragment(&header(0, 0, 2)), Ok(None));
+        assert_eq!(receiver.receive_fragment(&header(0, 0, 3)), Err("fragment count changed"));
+        //none of that was acked except the one good fragment
+        let acks = receiver.take_acks();
+        assert_eq!(acks[0][..2], [1, 0]);
+    }
+
+    #[test]
+    fn bad_acks_are_rejected(){
+        let mut sender = Channel::new();
+        sender.send(&message(5));
+        assert_eq!(sender.receive_acks(&[1]), Err("ack count cut off"));
+        assert_eq!(sender.receive_acks(&[1, 0, 0, 0]), Err("bad ack length"));
+        assert_eq!(sender.pending(), 1);
+    }
+
+    #[test]
+    fn unacked_fragments_are_resent(){
+        let mut sender = Channel::new();
+        let fragments = sender.send(&message(MAX_PAYLOAD+1));
+        assert!(sender.resend().is_empty());
+        for sent in sender.unacked.values_mut() {
+            sent.last_sent -= Duration::from_millis(2000);
+        }
+        let mut receiver = Channel::new();
+        receiver.receive_fragment(&fragments[1]).unwrap();
+        sender.receive_acks(&receiver.take_acks()[0]).unwrap();
+        assert_eq!(sender.resend(), vec![fragments[0].clone()]);
+        assert_eq!(sender.resent, 1);
+    }
+}
@@ -0,0 +1,133 @@
+use simulation::*;
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum VictoryCondition{
+    Elimination, //last team with anything left wins
+    Domination{planets: u32, ticks: u32}, //team owns at least this many planets for this many ticks in a row
+    TimeLimit(ChronalTime), //at this time, the team with the most units alive wins
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MatchResult{
+    pub winners: Vec<Player>, //the winning team, empty is a draw
+    pub condition: VictoryCondition,
+    pub time: ChronalTime,
+    pub metadata: SimMetadata, //final tallies at the deciding time
+}
+
+//Only ever fed times that have fallen off the left edge of the timeline, so that a result can't be undone
+#[derive(Serialize, Deserialize)]
+pub struct VictoryTracker{
+    pub conditions: Vec<VictoryCondition>,
+    pub players: Vec<Player>,
+    pub teams: PlayerArr<Team>,
+    domination_held: BTreeMap<Team, u32>,
+    pub result: Option<MatchResult>,
+}
+
+//players who own a planet at the start of the game
+pub fn starting_players(sim: &Simulation) -> Vec<Player>{
+    let mut players = Vec::new();
+    for node_ind in sim.world.node_indices(){
+        let owner = sim.world[node_ind].owner;
+        if owner != Player::PASSIVE && !players.contains(&owner){
+            players.push(owner);
+        }
+    }
+    players.sort();
+    players
+}
+
+impl VictoryTracker{
+    pub fn new(conditions: Vec<VictoryCondition>, players: Vec<Player>, teams: PlayerArr<Team>) -> VictoryTracker{
+        VictoryTracker{conditions, players, teams, domination_held: BTreeMap::new(), result: None}
+    }
+    //teams with a starting player, in order
+    pub fn team_list(&self) -> Vec<Team>{
+        let mut list: Vec<Team> = self.players.iter().map(|&p| self.teams[p]).collect();
+        list.sort();
+        list.dedup();
+        list
+    }
+    pub fn team_members(&self, team: Team) -> Vec<Player>{
+        self.players.iter().cloned().filter(|&p| self.teams[p] == team).collect()
+    }
+    fn team_total(&self, team: Team, arr: &PlayerArr<u32>) -> u32{
+        self.team_members(team).iter().map(|&p| arr[p]).sum()
+    }
+
+    pub fn check(&mut self, time: ChronalTime, metadata: &SimMetadata){
+        if self.result.is_some(){
+            return;
+        }
+        for condition in self.conditions.clone(){
+            let found = match condition{
+                VictoryCondition::Elimination => self.check_elimination(metadata),
+                VictoryCondition::Domination{planets, ticks} => self.check_domination(metadata, planets, ticks),
+                VictoryCondition::TimeLimit(limit) => self.check_time_limit(time, limit, metadata),
+            };
+            if let Some(winner) = found{
+                let winners = match winner{
+                    Some(team) => self.team_members(team),
+                    None => Vec::new(),
+                };
+                self.result = Some(MatchResult{winners, condition, time, metadata: metadata.clone()});
+                return;
+            }
+        }
+    }
+    //each of these returns Some(winning team) if the match is over, where a winner of None is a draw
+    fn check_elimination(&self, metadata: &SimMetadata) -> Option<Option<Team>>{
+        let teams = self.team_list();
+        if teams.len() < 2{
+            return None;//nobody to be eliminated against
+        }
+        let alive: Vec<Team> = teams.into_iter().filter(|&t| {
+            self.team_total(t, &metadata.total_living) > 0 || self.team_total(t, &metadata.planets_owned) > 0
+        }).collect();
+        match alive.len(){
+            0 => Some(None),
+            1 => Some(Some(alive[0])),
+            _ => None,
+        }
+    }
+    fn check_domination(&mut self, metadata: &SimMetadata, planets: u32, ticks: u32) -> Option<Option<Team>>{
+        let mut winner = None;
+        for team in self.team_list(){
+            if self.team_total(team, &metadata.planets_owned) >= planets{
+                let held = self.domination_held.entry(team).or_insert(0);
+                *held += 1;
+                if *held >= ticks{
+                    winner = Some(Some(team));
+                }
+            } else {
+                self.domination_held.insert(team, 0);
+            }
+        }
+        winner
+    }
+    fn check_time_limit(&self, time: ChronalTime, limit: ChronalTime, metadata: &SimMetadata) -> Option<Option<Team>>{
+        if time < limit{
+            return None;
+        }
+        let mut best = None;
+        let mut best_count = 0;
+        let mut tied = false;
+        for team in self.team_list(){
+            let living = self.team_total(team, &metadata.total_living);
+            if best.is_none() || living > best_count{
+                best = Some(team);
+                best_count = living;
+                tied = false;
+            } else if living == best_count{
+                tied = true;
+            }
+        }
+        if tied{
+            Some(None)
+        } else {
+            Some(best)
+        }
+    }
+}